@@ -0,0 +1,434 @@
+use tracing::info;
+
+use crate::Commander;
+use crate::crypt::our_mapping;
+use crate::detect::{DiskDetectorTrait, StaticDisks, container_path, device_size, lsblk};
+use crate::error::{Error, Result};
+use crate::lvm::{LvmController, VG_TAG};
+use crate::partition::{PartitionKind, PartitionLayout};
+use crate::remove_taint::remove_taint;
+use crate::swap::{SwapController, is_our_swap};
+use crate::zram;
+
+/// How to split the ephemeral disks between swap and the volume group.
+///
+/// On a rerun, splits of whole disks keep the disks that are already swap or in the volume group
+/// where they are, and are refused if the allocation would move them.
+#[derive(Clone, Debug, PartialEq)]
+pub enum DiskAllocation {
+    /// This many whole disks go to swap, in the order they were detected.
+    Count(usize),
+    /// Whole disks go to swap, in the order they were detected,
+    /// until they make up at least this percentage of the total capacity.
+    CapacityPercent(u8),
+    /// All disks go to the volume group, and swap goes on a logical volume in it,
+    /// sized by the swap controller's `swap_size`.
+    Volume,
+    /// Every disk is partitioned with the layout, which must have
+    /// both a swap and an LVM partition.
+    Partitions(PartitionLayout),
+}
+
+/// Sets up both swap and an LVM volume group on the ephemeral disks,
+/// removing the taint once both are done.
+///
+/// The controllers' own `remove_taint` is ignored, as is their disk detector,
+/// which is replaced by their share of the detected disks.
+pub struct CombinedController<D: DiskDetectorTrait> {
+    pub commander: Commander,
+    pub disk_detector: D,
+    pub node_name: Option<String>,
+    pub taint_key: String,
+    pub remove_taint: bool,
+    pub allocation: DiskAllocation,
+    pub lvm: LvmController<StaticDisks>,
+    pub swap: SwapController<StaticDisks>,
+}
+
+impl<D: DiskDetectorTrait> CombinedController<D> {
+    pub async fn setup(mut self) -> Result<()> {
+        info!("Starting NVMe disk configuration with LVM and swap...");
+        let previous = match self.allocation {
+            DiskAllocation::Count(_) | DiskAllocation::CapacityPercent(_) => {
+                previous_split(&self.commander, &self.lvm).await?
+            }
+            DiskAllocation::Volume | DiskAllocation::Partitions(_) => PreviousSplit::default(),
+        };
+        // Disks in the volume group aren't detected again once it has logical volumes,
+        // and aren't needed to add swap to it.
+        let devices =
+            if self.allocation == DiskAllocation::Volume && self.lvm.volume_group_exists().await? {
+                info!("Volume group {} already exists.", self.lvm.vg_name);
+                Vec::new()
+            } else if previous.is_empty() {
+                self.disk_detector.detect_devices().await?
+            } else {
+                info!(
+                    "Disks were already split into {:?} for swap and {:?} for LVM",
+                    previous.swap, previous.lvm
+                );
+                // Disks that are in use aren't detected again, and may all be in use.
+                let detected = match self.disk_detector.detect_devices().await {
+                    Err(Error::NoDisks(_)) => Vec::new(),
+                    detected => detected?,
+                };
+                previous.ordered(detected)
+            };
+        let (swap_devices, lvm_devices) = match &self.allocation {
+            DiskAllocation::Count(count) => split_by_count(devices, *count)?,
+            DiskAllocation::CapacityPercent(percent) => {
+                let mut sizes = Vec::with_capacity(devices.len());
+                for device in devices {
+                    let size = device_size(&self.commander, &device).await?;
+                    sizes.push((device, size));
+                }
+                split_by_capacity(sizes, *percent)?
+            }
+            DiskAllocation::Partitions(layout) => self.partition(layout, &devices).await?,
+            DiskAllocation::Volume => {
+                if self.swap.swap_size.is_none() {
+                    return Err(Error::Config(
+                        "swap on a logical volume needs a swap size".to_owned(),
+                    ));
+                }
+                self.swap.vg_name = self.lvm.vg_name.clone();
+                (devices.clone(), devices)
+            }
+        };
+        if self.allocation != DiskAllocation::Volume && self.swap.swap_size.is_some() {
+            return Err(Error::Config(
+                "a swap size can only be used with swap on a logical volume".to_owned(),
+            ));
+        }
+        previous.check(&swap_devices, &lvm_devices)?;
+        info!("Using {swap_devices:?} for swap and {lvm_devices:?} for LVM");
+
+        self.lvm.disk_detector = StaticDisks(lvm_devices);
+        self.lvm.remove_taint = false;
+        self.lvm.setup().await?;
+        self.swap.disk_detector = StaticDisks(swap_devices);
+        self.swap.remove_taint = false;
+        self.swap.setup().await?;
+
+        info!("Combined setup completed successfully");
+        if self.remove_taint {
+            remove_taint(
+                &self.commander,
+                self.node_name.as_ref().expect("clap enforced"),
+                &self.taint_key,
+            )
+            .await?;
+        }
+        Ok(())
+    }
+
+    /// Partitions the devices, returning the swap partitions and the LVM partitions.
+    async fn partition(
+        &self,
+        layout: &PartitionLayout,
+        devices: &[String],
+    ) -> Result<(Vec<String>, Vec<String>)> {
+        if !layout.contains(PartitionKind::Swap) || !layout.contains(PartitionKind::Lvm) {
+            return Err(Error::Config(
+                "the partition layout needs both a swap and an lvm partition".to_owned(),
+            ));
+        }
+        let mut swap_devices = Vec::new();
+        let mut lvm_devices = Vec::new();
+        for device in devices {
+            for (kind, partition) in layout.apply(&self.commander, device).await? {
+                match kind {
+                    PartitionKind::Swap => swap_devices.push(partition),
+                    PartitionKind::Lvm => lvm_devices.push(partition),
+                }
+            }
+        }
+        Ok((swap_devices, lvm_devices))
+    }
+}
+
+/// Whole disks that a previous run gave to swap or to the volume group.
+#[derive(Debug, Default, PartialEq)]
+struct PreviousSplit {
+    swap: Vec<String>,
+    lvm: Vec<String>,
+}
+
+impl PreviousSplit {
+    fn is_empty(&self) -> bool {
+        self.swap.is_empty() && self.lvm.is_empty()
+    }
+
+    /// Orders the disks so that splitting them again keeps the previous split,
+    /// with the detected disks that aren't in it yet in between.
+    fn ordered(&self, detected: Vec<String>) -> Vec<String> {
+        let unused = detected
+            .into_iter()
+            .filter(|device| !self.swap.contains(device) && !self.lvm.contains(device));
+        self.swap
+            .iter()
+            .cloned()
+            .chain(unused)
+            .chain(self.lvm.iter().cloned())
+            .collect()
+    }
+
+    /// Refuses a split that would move disks from swap to the volume group, or back.
+    fn check(&self, swap_devices: &[String], lvm_devices: &[String]) -> Result<()> {
+        if self.swap.iter().all(|device| swap_devices.contains(device))
+            && self.lvm.iter().all(|device| lvm_devices.contains(device))
+        {
+            return Ok(());
+        }
+        Err(Error::Refused(format!(
+            "disks were already split into {:?} for swap and {:?} for LVM, \
+             which the requested allocation doesn't match",
+            self.swap, self.lvm
+        )))
+    }
+}
+
+/// Finds the whole disks a previous run gave to swap and to the volume group,
+/// from our swap labels and the physical volumes of our volume group,
+/// on the disks or on our encrypted mappings over them.
+async fn previous_split(
+    commander: &Commander,
+    lvm: &LvmController<StaticDisks>,
+) -> Result<PreviousSplit> {
+    let physical_volumes = if lvm.volume_group_exists().await? {
+        if !lvm.volume_group_tagged().await? {
+            return Err(Error::Refused(format!(
+                "volume group {} isn't tagged {VG_TAG}",
+                lvm.vg_name
+            )));
+        }
+        lvm.physical_volumes().await?
+    } else {
+        Vec::new()
+    };
+    let devices = lsblk(commander, &["--output", "PATH,TYPE,FSTYPE,LABEL"]).await?;
+    let mut previous = PreviousSplit::default();
+    for disk in devices
+        .iter()
+        .filter(|device| device.type_ == "disk" && !zram::is_zram(&device.path))
+    {
+        let used = disk
+            .children
+            .as_deref()
+            .unwrap_or_default()
+            .iter()
+            .find(|child| our_mapping(&child.path).is_some())
+            .unwrap_or(disk);
+        if physical_volumes.contains(&used.path) {
+            previous.lvm.push(container_path(&disk.path)?);
+        } else if is_our_swap(used) {
+            previous.swap.push(container_path(&disk.path)?);
+        }
+    }
+    if previous.lvm.len() < physical_volumes.len() {
+        return Err(Error::Refused(format!(
+            "volume group {} has physical volumes that aren't whole disks, \
+             so the disks can't be split again",
+            lvm.vg_name
+        )));
+    }
+    Ok(previous)
+}
+
+/// Splits the devices into the first `count` for swap, and the rest for LVM.
+fn split_by_count(mut devices: Vec<String>, count: usize) -> Result<(Vec<String>, Vec<String>)> {
+    if count == 0 || count >= devices.len() {
+        return Err(Error::Config(format!(
+            "can't give {count} of {} disks to swap and leave some for LVM",
+            devices.len()
+        )));
+    }
+    let lvm_devices = devices.split_off(count);
+    Ok((devices, lvm_devices))
+}
+
+/// Splits the devices, with their sizes, so that swap gets at least `percent` of the capacity.
+fn split_by_capacity(
+    devices: Vec<(String, u64)>,
+    percent: u8,
+) -> Result<(Vec<String>, Vec<String>)> {
+    let total: u128 = devices.iter().map(|(_, size)| u128::from(*size)).sum();
+    let wanted = total * u128::from(percent) / 100;
+    let mut swap_capacity = 0;
+    let mut count = 0;
+    for (_, size) in &devices {
+        if swap_capacity >= wanted && count > 0 {
+            break;
+        }
+        swap_capacity += u128::from(*size);
+        count += 1;
+    }
+    let devices = devices.into_iter().map(|(device, _)| device).collect();
+    split_by_count(devices, count)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::TestEnv;
+
+    fn devices(devices: &[&str]) -> Vec<String> {
+        devices.iter().map(|device| device.to_string()).collect()
+    }
+
+    #[test]
+    fn test_split_by_count() {
+        let all = devices(&["/dev/nvme1n1", "/dev/nvme2n1", "/dev/nvme3n1"]);
+        assert_eq!(
+            split_by_count(all.clone(), 1).unwrap(),
+            (
+                devices(&["/dev/nvme1n1"]),
+                devices(&["/dev/nvme2n1", "/dev/nvme3n1"])
+            )
+        );
+        assert!(matches!(
+            split_by_count(all.clone(), 0),
+            Err(Error::Config(_))
+        ));
+        assert!(matches!(split_by_count(all, 3), Err(Error::Config(_))));
+    }
+
+    #[test]
+    fn test_split_by_capacity() {
+        let sized = |sizes: &[u64]| -> Vec<(String, u64)> {
+            sizes
+                .iter()
+                .enumerate()
+                .map(|(i, size)| (format!("/dev/nvme{}n1", i + 1), *size))
+                .collect()
+        };
+        assert_eq!(
+            split_by_capacity(sized(&[100, 100, 100, 100]), 50).unwrap(),
+            (
+                devices(&["/dev/nvme1n1", "/dev/nvme2n1"]),
+                devices(&["/dev/nvme3n1", "/dev/nvme4n1"])
+            )
+        );
+        // Rounds up to whole disks.
+        assert_eq!(
+            split_by_capacity(sized(&[100, 100, 100, 100]), 30).unwrap(),
+            (
+                devices(&["/dev/nvme1n1", "/dev/nvme2n1"]),
+                devices(&["/dev/nvme3n1", "/dev/nvme4n1"])
+            )
+        );
+        assert_eq!(
+            split_by_capacity(sized(&[100, 100, 100, 100]), 1).unwrap(),
+            (
+                devices(&["/dev/nvme1n1"]),
+                devices(&["/dev/nvme2n1", "/dev/nvme3n1", "/dev/nvme4n1"])
+            )
+        );
+        assert!(matches!(
+            split_by_capacity(sized(&[100, 100]), 100),
+            Err(Error::Config(_))
+        ));
+    }
+
+    fn lvm(commander: &Commander) -> LvmController<StaticDisks> {
+        LvmController {
+            commander: commander.clone(),
+            disk_detector: StaticDisks::default(),
+            node_name: None,
+            taint_key: "disk-unconfigured".to_owned(),
+            remove_taint: false,
+            vg_name: "instance-store-vg".to_owned(),
+            cache: None,
+            max_parallel_devices: 1,
+            encryption: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_rerun_keeps_previous_split() {
+        let test_env = TestEnv::new();
+        let lvm = lvm(&test_env.commander);
+        let mock_vg = |vg_tags: &str, pvs: &[&str]| {
+            test_env.mock(
+                "vgs",
+                0,
+                &format!(
+                    r#"{{"report": [{{"vg": [{{"vg_name": "instance-store-vg", "vg_tags": "{vg_tags}"}}]}}]}}"#
+                ),
+            );
+            let pvs: Vec<String> = pvs
+                .iter()
+                .map(|pv| format!(r#"{{"pv_name": "{pv}", "vg_name": "instance-store-vg"}}"#))
+                .collect();
+            test_env.mock(
+                "pvs",
+                0,
+                &format!(r#"{{"report": [{{"pv": [{}]}}]}}"#, pvs.join(", ")),
+            );
+        };
+        mock_vg(
+            "ephemeral-storage-setup",
+            &["/dev/nvme3n1", "/dev/mapper/ephemeral-crypt-nvme4n1"],
+        );
+        // After a reboot, swap on nvme2n1 is off, while the rest is still in use.
+        test_env.mock(
+            "lsblk",
+            0,
+            r#"{"blockdevices": [
+                {"path": "/dev/zram0", "type": "disk", "fstype": "swap", "label": "ephemeral-swap"},
+                {"path": "/dev/nvme1n1", "type": "disk", "children": [
+                    {"path": "/dev/mapper/ephemeral-crypt-nvme1n1", "type": "crypt", "fstype": "swap", "label": "ephemeral-swap"}
+                ]},
+                {"path": "/dev/nvme2n1", "type": "disk", "fstype": "swap", "label": "ephemeral-swap"},
+                {"path": "/dev/nvme3n1", "type": "disk", "fstype": "LVM2_member", "children": [
+                    {"path": "/dev/mapper/instance--store--vg-data", "type": "lvm"}
+                ]},
+                {"path": "/dev/nvme4n1", "type": "disk", "children": [
+                    {"path": "/dev/mapper/ephemeral-crypt-nvme4n1", "type": "crypt", "fstype": "LVM2_member"}
+                ]},
+                {"path": "/dev/nvme5n1", "type": "disk"}
+            ]}"#,
+        );
+        let previous = previous_split(&test_env.commander, &lvm).await.unwrap();
+        assert_eq!(
+            previous,
+            PreviousSplit {
+                swap: devices(&["/dev/nvme1n1", "/dev/nvme2n1"]),
+                lvm: devices(&["/dev/nvme3n1", "/dev/nvme4n1"]),
+            }
+        );
+
+        // Only the disks that aren't in use are detected again.
+        let ordered = previous.ordered(devices(&["/dev/nvme2n1", "/dev/nvme5n1"]));
+        let (swap, lvm_devices) = split_by_count(ordered.clone(), 2).unwrap();
+        assert_eq!(swap, devices(&["/dev/nvme1n1", "/dev/nvme2n1"]));
+        assert_eq!(
+            lvm_devices,
+            devices(&["/dev/nvme5n1", "/dev/nvme3n1", "/dev/nvme4n1"])
+        );
+        previous.check(&swap, &lvm_devices).unwrap();
+
+        // A count that would move a swap disk to the volume group is refused.
+        let (swap, lvm_devices) = split_by_count(ordered, 1).unwrap();
+        assert!(matches!(
+            previous.check(&swap, &lvm_devices),
+            Err(Error::Refused(_))
+        ));
+
+        // Physical volumes on partitions weren't split by disks.
+        mock_vg(
+            "ephemeral-storage-setup",
+            &["/dev/nvme3n1", "/dev/nvme5n1p2"],
+        );
+        assert!(matches!(
+            previous_split(&test_env.commander, &lvm).await,
+            Err(Error::Refused(_))
+        ));
+
+        mock_vg("some-other-tag", &["/dev/nvme3n1"]);
+        assert!(matches!(
+            previous_split(&test_env.commander, &lvm).await,
+            Err(Error::Refused(_))
+        ));
+    }
+}
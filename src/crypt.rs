@@ -0,0 +1,126 @@
+use tracing::info;
+
+use crate::Commander;
+use crate::error::{Error, Result};
+
+/// Prefix of the dm-crypt mappings we create, so we can recognize them later.
+pub(crate) const CRYPT_NAME_PREFIX: &str = "ephemeral-crypt-";
+
+/// Settings for encrypting devices with dm-crypt in plain mode.
+///
+/// Plain mode has no on-disk header, so there is nothing to find on the devices
+/// besides the encrypted data.
+#[derive(Clone, Debug)]
+pub struct CryptConfig {
+    /// Cipher specification, in the format cryptsetup takes.
+    pub cipher: String,
+    /// Key size in bits.
+    pub key_size: usize,
+    /// File to read the key from, like a mounted Kubernetes Secret.
+    /// If not set, a random key is read from /dev/urandom each time a device is opened,
+    /// and never stored, so the data is unreadable once the mapping is closed.
+    pub key_file: Option<String>,
+}
+
+impl CryptConfig {
+    /// Opens an encrypted mapping over the device, returning the path to the mapping.
+    /// If the mapping is already open, it's reused.
+    pub(crate) async fn open(&self, commander: &Commander, device: &str) -> Result<String> {
+        let name = mapping_name(device);
+        let path = mapping_path(&name);
+        if std::fs::exists(&path).map_err(|e| Error::io(format!("Failed to check {path}"), e))? {
+            info!("{device} is already encrypted as {path}");
+            return Ok(path);
+        }
+        info!("Encrypting {device} as {path} with {}", self.cipher);
+        let key_file = self.key_file.as_deref().unwrap_or("/dev/urandom");
+        commander
+            .mutating_output(&[
+                "cryptsetup",
+                "open",
+                "--type",
+                "plain",
+                "--cipher",
+                &self.cipher,
+                "--key-size",
+                &self.key_size.to_string(),
+                "--key-file",
+                key_file,
+                device,
+                &name,
+            ])
+            .await?;
+        Ok(path)
+    }
+}
+
+/// Closes one of our mappings, discarding its key.
+pub(crate) async fn close(commander: &Commander, name: &str) -> Result<()> {
+    info!("Closing encrypted mapping {name}");
+    commander
+        .mutating_output(&["cryptsetup", "close", name])
+        .await?;
+    Ok(())
+}
+
+/// Name of the mapping we create over the device.
+pub(crate) fn mapping_name(device: &str) -> String {
+    let base_name = device.rsplit('/').next().unwrap_or(device);
+    format!("{CRYPT_NAME_PREFIX}{base_name}")
+}
+
+pub(crate) fn mapping_path(name: &str) -> String {
+    format!("/dev/mapper/{name}")
+}
+
+/// If the path is one of our mappings, returns its name.
+pub(crate) fn our_mapping(path: &str) -> Option<&str> {
+    path.strip_prefix("/dev/mapper/")
+        .filter(|name| name.starts_with(CRYPT_NAME_PREFIX))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::TestEnv;
+
+    #[test]
+    fn test_mapping_names() {
+        assert_eq!(mapping_name("/dev/nvme1n1"), "ephemeral-crypt-nvme1n1");
+        assert_eq!(
+            mapping_name("/.bottlerocket/rootfs/dev/nvme1n1"),
+            "ephemeral-crypt-nvme1n1"
+        );
+        assert_eq!(
+            our_mapping("/dev/mapper/ephemeral-crypt-nvme1n1"),
+            Some("ephemeral-crypt-nvme1n1")
+        );
+        assert_eq!(our_mapping("/dev/mapper/someone-else"), None);
+        assert_eq!(our_mapping("/dev/nvme1n1"), None);
+    }
+
+    #[tokio::test]
+    async fn test_open() {
+        let test_env = TestEnv::new();
+        let args_path = test_env.temp_dir.path().join("args");
+        test_env.mock_script(
+            "cryptsetup",
+            &format!(r#"echo "$@" > {}"#, args_path.display()),
+        );
+        let config = CryptConfig {
+            cipher: "aes-xts-plain64".to_owned(),
+            key_size: 512,
+            key_file: None,
+        };
+        let path = config
+            .open(&test_env.commander, "/dev/nvme1n1")
+            .await
+            .unwrap();
+        assert_eq!(path, "/dev/mapper/ephemeral-crypt-nvme1n1");
+        assert_eq!(
+            std::fs::read_to_string(args_path).unwrap().trim(),
+            "open --type plain --cipher aes-xts-plain64 --key-size 512 \
+             --key-file /dev/urandom /dev/nvme1n1 ephemeral-crypt-nvme1n1"
+        );
+    }
+}
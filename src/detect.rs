@@ -1,6 +1,9 @@
 use serde::Deserialize;
 use tracing::{debug, info, trace};
 
+use crate::crypt::our_mapping;
+use crate::error::{Error, Result};
+use crate::partition::is_our_partition;
 use crate::{CloudProvider, Commander};
 
 const BOTTLEROCKET_ROOTFS_PATH: &str = "/.bottlerocket/rootfs";
@@ -9,23 +12,62 @@ const BOTTLEROCKET_ROOTFS_PATH: &str = "/.bottlerocket/rootfs";
 struct Lsblk {
     blockdevices: Vec<LsblkBlockDevice>,
 }
+
+/// A device as reported by `lsblk --json`. Columns that weren't asked for are `None`.
 #[derive(Deserialize, Debug, PartialEq)]
-struct LsblkBlockDevice {
-    children: Option<Vec<LsblkBlockDevice>>,
+pub(crate) struct LsblkBlockDevice {
+    pub(crate) children: Option<Vec<LsblkBlockDevice>>,
+    // Filesystem or signature on the device (swap, LVM2_member, etc...)
+    pub(crate) fstype: Option<String>,
+    // Filesystem or swap label.
+    pub(crate) label: Option<String>,
     // Arbitrary string identifying the device model.
     // Not all cloud providers set this to a reasonable value.
     // GCP :(
-    model: Option<String>,
-    mountpoint: Option<String>,
+    pub(crate) model: Option<String>,
+    pub(crate) mountpoint: Option<String>,
+    // GPT partition name and type GUID, for partitions.
+    pub(crate) partlabel: Option<String>,
+    pub(crate) parttype: Option<String>,
     // Device path (ie: /dev/nvme0n1)
     // Note that in bottlerocket, this still only starts with /dev,
     // even though we're in a container that has it in /.bottlerocket/rootfs/dev
-    path: String,
+    pub(crate) path: String,
     // Connection of device (nvme, sata, etc...)
-    tran: Option<String>,
-    // Type of device (disk, part, etc...)
+    pub(crate) tran: Option<String>,
+    // Type of device (disk, part, lvm, crypt, etc...)
     #[serde(rename = "type")]
-    type_: String,
+    pub(crate) type_: String,
+}
+
+/// Runs `lsblk --json` with the arguments, returning the top level devices.
+pub(crate) async fn lsblk(commander: &Commander, args: &[&str]) -> Result<Vec<LsblkBlockDevice>> {
+    let mut full_args = vec!["lsblk", "--json"];
+    full_args.extend(args);
+    let output = commander.check_output(&full_args).await?;
+    trace!(
+        "lsblk block devices:\n{}",
+        String::from_utf8_lossy(&output.stdout)
+    );
+    serde_json::from_slice::<Lsblk>(&output.stdout)
+        .map(|lsblk| lsblk.blockdevices)
+        .map_err(|e| {
+            Error::parse(
+                format!("Failed to deserialize output of '{full_args:?}'"),
+                e,
+            )
+        })
+}
+
+/// Size of the device in bytes.
+pub(crate) async fn device_size(commander: &Commander, device: &str) -> Result<u64> {
+    let output = commander
+        .check_output(&["blockdev", "--getsize64", device])
+        .await?;
+    String::from_utf8_lossy(&output.stdout)
+        .trim()
+        .parse()
+        .map_err(|e| Error::parse(format!("Invalid size of {device}"), e))
 }
 
 trait LsblkIteratorExt {
@@ -56,33 +98,65 @@ impl<I: Iterator<Item = LsblkBlockDevice>> LsblkIteratorExt for I {
     }
 }
 
+/// Whether we're running on Bottlerocket, which exposes its root filesystem to containers.
+pub(crate) fn is_bottlerocket() -> Result<bool> {
+    std::fs::exists(BOTTLEROCKET_ROOTFS_PATH)
+        .map_err(|e| Error::io(format!("Failed to check for {BOTTLEROCKET_ROOTFS_PATH}"), e))
+}
+
+/// Where a device lsblk reports can be found from our container.
+pub(crate) fn container_path(path: &str) -> Result<String> {
+    Ok(if is_bottlerocket()? {
+        format!("{BOTTLEROCKET_ROOTFS_PATH}{path}")
+    } else {
+        path.to_owned()
+    })
+}
+
 pub trait DiskDetectorTrait {
-    fn detect_devices(&self) -> Vec<String>;
+    fn detect_devices(&self) -> impl Future<Output = Result<Vec<String>>> + Send;
+}
+
+/// A fixed list of devices, like the share of the disks given to one controller.
+#[derive(Clone, Debug, Default)]
+pub struct StaticDisks(pub Vec<String>);
+
+impl DiskDetectorTrait for StaticDisks {
+    async fn detect_devices(&self) -> Result<Vec<String>> {
+        Ok(self.0.clone())
+    }
+}
+
+impl<D: DiskDetectorTrait> DiskDetectorTrait for &D {
+    fn detect_devices(&self) -> impl Future<Output = Result<Vec<String>>> + Send {
+        (**self).detect_devices()
+    }
 }
 
 impl DiskDetectorTrait for DiskDetector {
-    fn detect_devices(&self) -> Vec<String> {
+    async fn detect_devices(&self) -> Result<Vec<String>> {
         info!(
             "Detecting disks for cloud provider: {:?}",
             self.cloud_provider
         );
         let devices = match self.cloud_provider {
-            CloudProvider::Aws => self.detect_aws_devices(),
-            CloudProvider::Gcp => self.detect_gcp_devices(),
-            CloudProvider::Azure => self.detect_azure_devices(),
-            CloudProvider::Generic => self.detect_generic_devices(),
+            CloudProvider::Aws => self.detect_aws_devices().await?,
+            CloudProvider::Gcp => self.detect_gcp_devices().await?,
+            CloudProvider::Azure => self.detect_azure_devices().await?,
+            CloudProvider::Generic => self.detect_generic_devices().await?,
         };
         if devices.is_empty() {
-            panic!("No suitable NVMe devices found");
+            return Err(Error::NoDisks(self.cloud_provider));
         }
         info!("Found devices: {:?}", &devices);
-        devices
+        Ok(devices)
     }
 }
 
 pub struct DiskDetector {
     cloud_provider: CloudProvider,
     commander: Commander,
+    include_ours: bool,
 }
 
 impl DiskDetector {
@@ -90,29 +164,52 @@ impl DiskDetector {
         DiskDetector {
             cloud_provider,
             commander,
+            include_ours: true,
         }
     }
-    fn lsblk(&self) -> impl Iterator<Item = LsblkBlockDevice> {
-        let output = self
-            .commander
-            .check_output(&["lsblk", "--json", "--output-all"]);
-        let lsblk_blockdevices = serde_json::from_slice::<Lsblk>(&output.stdout)
-            .expect("Failed to deserialize output of 'lsblk --json --output-all'")
-            .blockdevices;
-        trace!(
-            "lsblk block devices:\n{}",
-            String::from_utf8_lossy(&output.stdout)
-        );
-        lsblk_blockdevices.into_iter().filter(|device| {
+
+    /// Also excludes disks with our own encrypted mappings or partitions on them,
+    /// and whole disks that are physical volumes or swap,
+    /// which are still in use until teardown removes them.
+    pub fn excluding_ours(mut self) -> Self {
+        self.include_ours = false;
+        self
+    }
+
+    async fn lsblk(&self) -> Result<impl Iterator<Item = LsblkBlockDevice>> {
+        let lsblk_blockdevices = lsblk(&self.commander, &["--output-all"]).await?;
+        Ok(lsblk_blockdevices.into_iter().filter(|device| {
             if device.mountpoint.is_some() {
                 debug!("Excluding device '{}' because it is mounted.", &device.path);
                 return false;
             }
 
+            // A physical volume without logical volumes, or swap that's off, has neither.
+            if !self.include_ours
+                && matches!(device.fstype.as_deref(), Some("LVM2_member" | "swap"))
+            {
+                debug!(
+                    "Excluding device '{}' because it is a physical volume or swap.",
+                    &device.path
+                );
+                return false;
+            }
+
+            // Our own encrypted mappings and partitions don't count,
+            // so we find the devices again on a rerun.
             if device
                 .children
                 .as_ref()
-                .map(|children| !children.is_empty())
+                .map(|children| {
+                    children.iter().any(|child| {
+                        !self.include_ours
+                            || (our_mapping(&child.path).is_none()
+                                && !is_our_partition(
+                                    child.parttype.as_deref(),
+                                    child.partlabel.as_deref(),
+                                ))
+                    })
+                })
                 .unwrap_or(false)
             {
                 debug!(
@@ -139,14 +236,15 @@ impl DiskDetector {
             }
 
             true
-        })
+        }))
     }
 
-    fn find(&self, dir: &str, name: &str) -> Vec<String> {
+    async fn find(&self, dir: &str, name: &str) -> Result<Vec<String>> {
         let mut devices: Vec<String> = String::from_utf8_lossy(
             &self
                 .commander
                 .check_output(&["find", dir, "-name", name])
+                .await?
                 .stdout,
         )
         .trim()
@@ -159,49 +257,51 @@ impl DiskDetector {
             // and compare with lsblk output.
             #[cfg(not(test))]
             return std::fs::canonicalize(line)
-                .unwrap()
-                .to_str()
-                .unwrap()
-                .to_owned();
+                .map(|path| path.to_string_lossy().into_owned())
+                .map_err(|e| Error::io(format!("Failed to resolve {line}"), e));
 
             #[cfg(test)]
             {
                 let ordinal = line.chars().last().unwrap();
-                format!("/dev/nvme{ordinal}n1")
+                Ok(format!("/dev/nvme{ordinal}n1"))
             }
         })
-        .collect();
+        .collect::<Result<_>>()?;
         devices.sort();
         devices.dedup();
         trace!(
             "found devices in {dir} matching name {name}:\n{:?}",
             &devices
         );
-        devices
+        Ok(devices)
     }
 
-    fn detect_aws_devices(&self) -> Vec<String> {
-        if std::fs::exists(BOTTLEROCKET_ROOTFS_PATH).unwrap() {
-            self.detect_aws_bottlerocket_devices()
+    async fn detect_aws_devices(&self) -> Result<Vec<String>> {
+        if is_bottlerocket()? {
+            self.detect_aws_bottlerocket_devices().await
         } else {
-            self.detect_aws_standard_devices()
+            self.detect_aws_standard_devices().await
         }
     }
 
-    fn detect_aws_bottlerocket_devices(&self) -> Vec<String> {
-        self.detect_aws_standard_devices()
+    async fn detect_aws_bottlerocket_devices(&self) -> Result<Vec<String>> {
+        Ok(self
+            .detect_aws_standard_devices()
+            .await?
             .into_iter()
             .map(|path| format!("{BOTTLEROCKET_ROOTFS_PATH}{path}"))
-            .collect()
+            .collect())
     }
-    fn detect_aws_standard_devices(&self) -> Vec<String> {
-        self.lsblk()
+    async fn detect_aws_standard_devices(&self) -> Result<Vec<String>> {
+        Ok(self
+            .lsblk()
+            .await?
             .filter_model("Amazon EC2 NVMe Instance Storage")
             .paths()
-            .collect()
+            .collect())
     }
 
-    fn detect_gcp_devices(&self) -> Vec<String> {
+    async fn detect_gcp_devices(&self) -> Result<Vec<String>> {
         // `lsblk` doesn't contain a descriptive model for
         // GCP devices, so out of paranoia, we use `find` to
         // filter to local SSDs. We don't only use `find`
@@ -211,23 +311,27 @@ impl DiskDetector {
         // We'll make the assumption that the machine has homogeneous
         // disk setup, and that the disks the user configured or are
         // provided by the machine are NVME or equivilently fast.
-        let find_paths = self.find("/dev/disk/by-id", "google-local-*");
+        let find_paths = self.find("/dev/disk/by-id", "google-local-*").await?;
 
-        self.lsblk()
+        Ok(self
+            .lsblk()
+            .await?
             .paths()
             .filter(|path| find_paths.contains(path))
-            .collect()
+            .collect())
     }
 
-    fn detect_azure_devices(&self) -> Vec<String> {
-        self.lsblk()
+    async fn detect_azure_devices(&self) -> Result<Vec<String>> {
+        Ok(self
+            .lsblk()
+            .await?
             .filter_model("Microsoft NVMe Direct Disk")
             .paths()
-            .collect()
+            .collect())
     }
 
-    fn detect_generic_devices(&self) -> Vec<String> {
-        self.lsblk().paths().collect()
+    async fn detect_generic_devices(&self) -> Result<Vec<String>> {
+        Ok(self.lsblk().await?.paths().collect())
     }
 }
 
@@ -237,8 +341,8 @@ mod test {
     use crate::detect::{DiskDetector, LsblkBlockDevice};
     use crate::test::TestEnv;
 
-    #[test]
-    fn test_lsblk_filters() {
+    #[tokio::test]
+    async fn test_lsblk_filters() {
         let test_env = TestEnv::new();
         let disk_detector = DiskDetector::new(test_env.commander.clone(), CloudProvider::Aws);
 
@@ -249,6 +353,10 @@ mod test {
                 children: Some(vec![]),
                 model: Some("Amazon EC2 NVMe Instance Storage".to_owned()),
                 mountpoint: None,
+                fstype: None,
+                label: None,
+                partlabel: None,
+                parttype: None,
                 path: "/dev/nvme0n1".to_owned(),
                 tran: Some("nvme".to_owned()),
                 type_: "disk".to_owned(),
@@ -257,6 +365,10 @@ mod test {
                 children: Some(vec![]),
                 model: Some("Amazon EC2 NVMe Instance Storage".to_owned()),
                 mountpoint: None,
+                fstype: None,
+                label: None,
+                partlabel: None,
+                parttype: None,
                 path: "/dev/nvme1n1".to_owned(),
                 tran: Some("nvme".to_owned()),
                 type_: "disk".to_owned(),
@@ -265,6 +377,10 @@ mod test {
                 children: Some(vec![]),
                 model: Some("some other model".to_owned()),
                 mountpoint: None,
+                fstype: None,
+                label: None,
+                partlabel: None,
+                parttype: None,
                 path: "/dev/nvme2n1".to_owned(),
                 tran: Some("nvme".to_owned()),
                 type_: "disk".to_owned(),
@@ -273,6 +389,10 @@ mod test {
                 children: None,
                 model: Some("Amazon EC2 NVMe Instance Storage".to_owned()),
                 mountpoint: None,
+                fstype: None,
+                label: None,
+                partlabel: None,
+                parttype: None,
                 path: "/dev/nvme7n1".to_owned(),
                 tran: Some("nvme".to_owned()),
                 type_: "disk".to_owned(),
@@ -281,6 +401,10 @@ mod test {
                 children: None,
                 model: Some("Microsoft NVMe Direct Disk v49990322".to_owned()),
                 mountpoint: None,
+                fstype: None,
+                label: None,
+                partlabel: None,
+                parttype: None,
                 path: "/dev/nvme8n1".to_owned(),
                 tran: Some("nvme".to_owned()),
                 type_: "disk".to_owned(),
@@ -289,12 +413,16 @@ mod test {
                 children: None,
                 model: Some("nvme_card".to_owned()),
                 mountpoint: None,
+                fstype: None,
+                label: None,
+                partlabel: None,
+                parttype: None,
                 path: "/dev/nvme9n1".to_owned(),
                 tran: Some("nvme".to_owned()),
                 type_: "disk".to_owned(),
             },
         ];
-        let actual: Vec<LsblkBlockDevice> = disk_detector.lsblk().collect();
+        let actual: Vec<LsblkBlockDevice> = disk_detector.lsblk().await.unwrap().collect();
         assert_eq!(expected, actual);
 
         let lsblk_output = test_env.read_testdata("testdata/aws/lsblk.json");
@@ -303,11 +431,15 @@ mod test {
             children: None,
             model: Some("Amazon EC2 NVMe Instance Storage        ".to_owned()),
             mountpoint: None,
+            fstype: None,
+            label: None,
+            partlabel: None,
+            parttype: None,
             path: "/dev/nvme1n1".to_owned(),
             tran: Some("nvme".to_owned()),
             type_: "disk".to_owned(),
         }];
-        let actual: Vec<LsblkBlockDevice> = disk_detector.lsblk().collect();
+        let actual: Vec<LsblkBlockDevice> = disk_detector.lsblk().await.unwrap().collect();
         assert_eq!(expected, actual);
 
         let lsblk_output = test_env.read_testdata("testdata/azure/lsblk.json");
@@ -316,23 +448,30 @@ mod test {
             children: None,
             model: Some("Microsoft NVMe Direct Disk v2           ".to_owned()),
             mountpoint: None,
+            fstype: Some("LVM2_member".to_owned()),
+            label: None,
+            partlabel: None,
+            parttype: None,
             path: "/dev/nvme0n1".to_owned(),
             tran: Some("nvme".to_owned()),
             type_: "disk".to_owned(),
         }];
-        let actual: Vec<LsblkBlockDevice> = disk_detector.lsblk().collect();
+        let actual: Vec<LsblkBlockDevice> = disk_detector.lsblk().await.unwrap().collect();
         assert_eq!(expected, actual);
     }
 
-    #[test]
-    fn test_detect_aws_bottlerocket_devices() {
+    #[tokio::test]
+    async fn test_detect_aws_bottlerocket_devices() {
         let test_env = TestEnv::new();
         let disk_detector = DiskDetector::new(test_env.commander.clone(), CloudProvider::Aws);
 
         let lsblk_output = test_env.read_testdata("testdata/aws/lsblk.json");
         test_env.mock("lsblk", 0, &lsblk_output);
         let expected = vec!["/.bottlerocket/rootfs/dev/nvme1n1".to_owned()];
-        let actual = disk_detector.detect_aws_bottlerocket_devices();
+        let actual = disk_detector
+            .detect_aws_bottlerocket_devices()
+            .await
+            .unwrap();
         assert_eq!(expected, actual);
 
         let lsblk_output = test_env.read_testdata("testdata/lsblk_contrived.json");
@@ -342,19 +481,22 @@ mod test {
             "/.bottlerocket/rootfs/dev/nvme1n1".to_owned(),
             "/.bottlerocket/rootfs/dev/nvme7n1".to_owned(),
         ];
-        let actual = disk_detector.detect_aws_bottlerocket_devices();
+        let actual = disk_detector
+            .detect_aws_bottlerocket_devices()
+            .await
+            .unwrap();
         assert_eq!(expected, actual);
     }
 
-    #[test]
-    fn test_detect_aws_standard_devices() {
+    #[tokio::test]
+    async fn test_detect_aws_standard_devices() {
         let test_env = TestEnv::new();
         let disk_detector = DiskDetector::new(test_env.commander.clone(), CloudProvider::Aws);
 
         let lsblk_output = test_env.read_testdata("testdata/aws/lsblk.json");
         test_env.mock("lsblk", 0, &lsblk_output);
         let expected = vec!["/dev/nvme1n1".to_owned()];
-        let actual = disk_detector.detect_aws_standard_devices();
+        let actual = disk_detector.detect_aws_standard_devices().await.unwrap();
         assert_eq!(expected, actual);
 
         let lsblk_output = test_env.read_testdata("testdata/lsblk_contrived.json");
@@ -364,30 +506,158 @@ mod test {
             "/dev/nvme1n1".to_owned(),
             "/dev/nvme7n1".to_owned(),
         ];
-        let actual = disk_detector.detect_aws_standard_devices();
+        let actual = disk_detector.detect_aws_standard_devices().await.unwrap();
         assert_eq!(expected, actual);
     }
 
-    #[test]
-    fn test_detect_azure_devices() {
+    #[tokio::test]
+    async fn test_detect_encrypted_devices() {
+        let test_env = TestEnv::new();
+        let disk_detector = DiskDetector::new(test_env.commander.clone(), CloudProvider::Generic);
+        let device = |path: &str, type_: &str, mountpoint: &str, children: &str| {
+            format!(
+                r#"{{"path": "{path}", "type": "{type_}", "tran": "nvme", "model": null,
+                    "mountpoint": {mountpoint}, "children": [{children}]}}"#
+            )
+        };
+        let ours = device(
+            "/dev/mapper/ephemeral-crypt-nvme1n1",
+            "crypt",
+            r#""[SWAP]""#,
+            "",
+        );
+        let theirs = device("/dev/mapper/data", "crypt", "null", "");
+        let lsblk_output = format!(
+            r#"{{"blockdevices": [{}, {}]}}"#,
+            device("/dev/nvme1n1", "disk", "null", &ours),
+            device("/dev/nvme2n1", "disk", "null", &theirs),
+        );
+        test_env.mock("lsblk", 0, &lsblk_output);
+        // Devices under our own mappings are found again, but not those under anyone else's.
+        let actual = disk_detector.detect_generic_devices().await.unwrap();
+        assert_eq!(vec!["/dev/nvme1n1".to_owned()], actual);
+    }
+
+    #[tokio::test]
+    async fn test_detect_partitioned_devices() {
+        let test_env = TestEnv::new();
+        let disk_detector = DiskDetector::new(test_env.commander.clone(), CloudProvider::Generic);
+        let partition = |path: &str, parttype: &str, partlabel: &str| {
+            format!(
+                r#"{{"path": "{path}", "type": "part", "tran": "nvme", "model": null,
+                    "mountpoint": null, "parttype": "{parttype}", "partlabel": "{partlabel}"}}"#
+            )
+        };
+        let disk = |path: &str, children: &str| {
+            format!(
+                r#"{{"path": "{path}", "type": "disk", "tran": "nvme", "model": null,
+                    "mountpoint": null, "children": [{children}]}}"#
+            )
+        };
+        let ours = [
+            partition(
+                "/dev/nvme1n1p1",
+                "0657fd6d-a4ab-43c4-84e5-0933c84b4f4f",
+                "ephemeral-swap",
+            ),
+            partition(
+                "/dev/nvme1n1p2",
+                "e6d6d379-f507-44c2-a23c-238f2a3df928",
+                "ephemeral-lvm",
+            ),
+        ]
+        .join(",");
+        // Our label, but not a swap partition.
+        let theirs = partition(
+            "/dev/nvme2n1p1",
+            "0fc63daf-8483-4772-8e79-3d69d8477de4",
+            "ephemeral-swap",
+        );
+        let lsblk_output = format!(
+            r#"{{"blockdevices": [{}, {}]}}"#,
+            disk("/dev/nvme1n1", &ours),
+            disk("/dev/nvme2n1", &theirs),
+        );
+        test_env.mock("lsblk", 0, &lsblk_output);
+        let actual = disk_detector.detect_generic_devices().await.unwrap();
+        assert_eq!(vec!["/dev/nvme1n1".to_owned()], actual);
+    }
+
+    #[tokio::test]
+    async fn test_detect_excluding_ours() {
+        let test_env = TestEnv::new();
+        let disk_detector =
+            DiskDetector::new(test_env.commander.clone(), CloudProvider::Generic).excluding_ours();
+        let device = |path: &str, type_: &str, mountpoint: &str, extra: &str| {
+            format!(
+                r#"{{"path": "{path}", "type": "{type_}", "tran": "nvme", "model": null,
+                    "mountpoint": {mountpoint}{extra}}}"#
+            )
+        };
+        let mapping = device(
+            "/dev/mapper/ephemeral-crypt-nvme1n1",
+            "crypt",
+            r#""[SWAP]""#,
+            "",
+        );
+        let partition = device(
+            "/dev/nvme2n1p1",
+            "part",
+            "null",
+            r#", "parttype": "0657fd6d-a4ab-43c4-84e5-0933c84b4f4f", "partlabel": "ephemeral-swap""#,
+        );
+        let lsblk_output = format!(
+            r#"{{"blockdevices": [{}, {}, {}, {}, {}]}}"#,
+            device(
+                "/dev/nvme1n1",
+                "disk",
+                "null",
+                &format!(r#", "children": [{mapping}]"#)
+            ),
+            device(
+                "/dev/nvme2n1",
+                "disk",
+                "null",
+                &format!(r#", "children": [{partition}]"#)
+            ),
+            device("/dev/nvme3n1", "disk", r#""[SWAP]""#, ""),
+            device("/dev/nvme4n1", "disk", "null", r#", "children": []"#),
+            device("/dev/nvme5n1", "disk", "null", r#", "fstype": "swap""#),
+        );
+        test_env.mock("lsblk", 0, &lsblk_output);
+        // Only the disk with nothing on it is free to erase.
+        let actual = disk_detector.detect_generic_devices().await.unwrap();
+        assert_eq!(vec!["/dev/nvme4n1".to_owned()], actual);
+    }
+
+    #[tokio::test]
+    async fn test_detect_azure_devices() {
         let test_env = TestEnv::new();
         let disk_detector = DiskDetector::new(test_env.commander.clone(), CloudProvider::Azure);
 
         let lsblk_output = test_env.read_testdata("testdata/azure/lsblk.json");
         test_env.mock("lsblk", 0, &lsblk_output);
         let expected = vec!["/dev/nvme0n1".to_owned()];
-        let actual = disk_detector.detect_azure_devices();
+        let actual = disk_detector.detect_azure_devices().await.unwrap();
         assert_eq!(expected, actual);
 
+        // The disk is a physical volume, even without logical volumes on it.
+        let actual = DiskDetector::new(test_env.commander.clone(), CloudProvider::Azure)
+            .excluding_ours()
+            .detect_azure_devices()
+            .await
+            .unwrap();
+        assert!(actual.is_empty());
+
         let lsblk_output = test_env.read_testdata("testdata/lsblk_contrived.json");
         test_env.mock("lsblk", 0, &lsblk_output);
         let expected = vec!["/dev/nvme8n1".to_owned()];
-        let actual = disk_detector.detect_azure_devices();
+        let actual = disk_detector.detect_azure_devices().await.unwrap();
         assert_eq!(expected, actual);
     }
 
-    #[test]
-    fn test_detect_gcp_devices() {
+    #[tokio::test]
+    async fn test_detect_gcp_devices() {
         let test_env = TestEnv::new();
         let disk_detector = DiskDetector::new(test_env.commander.clone(), CloudProvider::Gcp);
 
@@ -407,7 +677,7 @@ mod test {
 "#,
         );
         let expected = vec!["/dev/nvme0n1".to_owned()];
-        let actual = disk_detector.detect_gcp_devices();
+        let actual = disk_detector.detect_gcp_devices().await.unwrap();
         assert_eq!(expected, actual);
 
         test_env.mock(
@@ -422,7 +692,7 @@ mod test {
         let lsblk_output = test_env.read_testdata("testdata/lsblk_contrived.json");
         test_env.mock("lsblk", 0, &lsblk_output);
         let expected = vec!["/dev/nvme2n1".to_owned(), "/dev/nvme9n1".to_owned()];
-        let actual = disk_detector.detect_gcp_devices();
+        let actual = disk_detector.detect_gcp_devices().await.unwrap();
         assert_eq!(expected, actual);
     }
 }
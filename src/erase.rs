@@ -0,0 +1,593 @@
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom};
+use std::os::fd::AsRawFd;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use serde::Serialize;
+use tracing::{info, warn};
+
+use crate::detect::DiskDetectorTrait;
+use crate::error::{Error, Result};
+use crate::{CommandPolicy, Commander};
+
+// From linux/nvme_ioctl.h
+// _IO('N', 0x40)
+const NVME_IOCTL_ID: u32 = 0x4E40;
+// _IOWR('N', 0x41, struct nvme_admin_cmd)
+const NVME_IOCTL_ADMIN_CMD: u32 = 0xC048_4E41;
+
+// Admin command opcodes from the NVMe base specification.
+const NVME_ADMIN_GET_LOG_PAGE: u8 = 0x02;
+const NVME_ADMIN_IDENTIFY: u8 = 0x06;
+const NVME_ADMIN_FORMAT_NVM: u8 = 0x80;
+const NVME_ADMIN_SANITIZE: u8 = 0x84;
+
+const NVME_LOG_SANITIZE_STATUS: u32 = 0x81;
+const NVME_IDENTIFY_CNS_NAMESPACE: u32 = 0x00;
+
+// Sanitize Action (SANACT) values.
+const NVME_SANACT_BLOCK_ERASE: u32 = 0x2;
+const NVME_SANACT_CRYPTO_ERASE: u32 = 0x4;
+
+// Secure Erase Settings (SES) values for Format NVM.
+const NVME_SES_USER_DATA_ERASE: u32 = 0x1;
+const NVME_SES_CRYPTO_ERASE: u32 = 0x2;
+
+// Sanitize Status (SSTAT) values from the sanitize status log page.
+const NVME_SANITIZE_STATUS_COMPLETED: u16 = 0x1;
+const NVME_SANITIZE_STATUS_IN_PROGRESS: u16 = 0x2;
+const NVME_SANITIZE_STATUS_COMPLETED_NO_DEALLOCATE: u16 = 0x4;
+
+// Sanitize and format can take a long time on large devices.
+const NVME_FORMAT_TIMEOUT: Duration = Duration::from_secs(600);
+const NVME_SANITIZE_TIMEOUT: Duration = Duration::from_secs(3600);
+
+const VERIFY_SAMPLE_SIZE: usize = 4096;
+
+// struct nvme_admin_cmd from linux/nvme_ioctl.h
+#[repr(C)]
+#[derive(Default)]
+struct NvmeAdminCmd {
+    opcode: u8,
+    flags: u8,
+    rsvd1: u16,
+    nsid: u32,
+    cdw2: u32,
+    cdw3: u32,
+    metadata: u64,
+    addr: u64,
+    metadata_len: u32,
+    data_len: u32,
+    cdw10: u32,
+    cdw11: u32,
+    cdw12: u32,
+    cdw13: u32,
+    cdw14: u32,
+    cdw15: u32,
+    timeout_ms: u32,
+    result: u32,
+}
+
+/// Ways of erasing a device, in the order we try them.
+#[derive(Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum EraseMethod {
+    NvmeSanitizeCryptoErase,
+    NvmeSanitizeBlockErase,
+    NvmeFormatCryptoErase,
+    NvmeFormatUserDataErase,
+    BlkdiscardSecure,
+    Blkdiscard,
+}
+
+impl EraseMethod {
+    /// Whether the method only throws away the encryption key, leaving the old data
+    /// unreadable, but not zeroed, so it can't be verified by reading it back.
+    fn is_crypto_erase(&self) -> bool {
+        matches!(
+            self,
+            EraseMethod::NvmeSanitizeCryptoErase | EraseMethod::NvmeFormatCryptoErase
+        )
+    }
+}
+
+const ERASE_METHODS: [EraseMethod; 6] = [
+    EraseMethod::NvmeSanitizeCryptoErase,
+    EraseMethod::NvmeSanitizeBlockErase,
+    EraseMethod::NvmeFormatCryptoErase,
+    EraseMethod::NvmeFormatUserDataErase,
+    EraseMethod::BlkdiscardSecure,
+    EraseMethod::Blkdiscard,
+];
+
+/// Machine-readable record of an erase run.
+#[derive(Serialize, Debug)]
+pub struct EraseReport {
+    pub node_name: Option<String>,
+    /// Seconds since the unix epoch.
+    pub started_at: u64,
+    /// Seconds since the unix epoch.
+    pub finished_at: u64,
+    pub devices: Vec<DeviceEraseReport>,
+    pub success: bool,
+}
+
+#[derive(Serialize, Debug)]
+pub struct DeviceEraseReport {
+    pub device: String,
+    /// Every method we tried, in order. All but the last one failed.
+    pub attempts: Vec<EraseAttempt>,
+    /// The method that successfully erased the device, if any.
+    pub method: Option<EraseMethod>,
+    /// Skipped after a crypto erase, which leaves the old data unreadable rather than zeroed.
+    pub verification: Option<Verification>,
+    pub success: bool,
+}
+
+#[derive(Serialize, Debug)]
+pub struct EraseAttempt {
+    pub method: EraseMethod,
+    pub error: Option<String>,
+    pub duration_ms: u128,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct Verification {
+    /// Number of blocks read back from the device.
+    pub samples: usize,
+    /// Size in bytes of each block read back.
+    pub sample_size: usize,
+    /// Byte offsets of samples that still contained data.
+    pub dirty_offsets: Vec<u64>,
+    pub passed: bool,
+}
+
+/// Erases the detected ephemeral disks, so no data on them survives the node.
+///
+/// Erase is attempted with NVMe sanitize, then NVMe format with secure erase,
+/// then blkdiscard, and verified by sampling reads across the device afterwards,
+/// unless it was a crypto erase.
+pub struct EraseController<D: DiskDetectorTrait> {
+    pub commander: Commander,
+    pub disk_detector: D,
+    pub node_name: Option<String>,
+    pub report_path: Option<String>,
+    pub verify_samples: usize,
+}
+
+impl<D: DiskDetectorTrait> EraseController<D> {
+    pub async fn erase(&self) -> Result<()> {
+        info!("Starting erase of ephemeral disks...");
+        let started_at = unix_now();
+        let mut devices = Vec::new();
+        for device in self.disk_detector.detect_devices().await? {
+            if self.commander.should_perform(format!(
+                "erase {device} with the first of {ERASE_METHODS:?} that succeeds"
+            )) {
+                devices.push(self.erase_device(device).await);
+            }
+        }
+        if self.commander.is_dry_run() {
+            return Ok(());
+        }
+        let report = EraseReport {
+            node_name: self.node_name.clone(),
+            started_at,
+            finished_at: unix_now(),
+            success: devices.iter().all(|device| device.success),
+            devices,
+        };
+
+        let report_json = serde_json::to_string(&report).unwrap();
+        println!("{report_json}");
+        if let Some(report_path) = &self.report_path {
+            std::fs::write(report_path, &report_json).map_err(|e| {
+                Error::io(format!("Failed to write erase report to {report_path}"), e)
+            })?;
+        }
+
+        if !report.success {
+            return Err(Error::Erase(
+                report
+                    .devices
+                    .into_iter()
+                    .filter(|device| !device.success)
+                    .map(|device| device.device)
+                    .collect(),
+            ));
+        }
+        info!("Erase completed successfully");
+        Ok(())
+    }
+
+    async fn erase_device(&self, device: String) -> DeviceEraseReport {
+        let mut attempts = Vec::new();
+        let mut method = None;
+        for candidate in ERASE_METHODS {
+            info!("Erasing {device} with {candidate:?}");
+            let start = Instant::now();
+            let result = self.erase_with(&device, candidate).await;
+            let duration_ms = start.elapsed().as_millis();
+            match result {
+                Ok(()) => {
+                    attempts.push(EraseAttempt {
+                        method: candidate,
+                        error: None,
+                        duration_ms,
+                    });
+                    method = Some(candidate);
+                    break;
+                }
+                Err(error) => {
+                    warn!("Failed to erase {device} with {candidate:?}: {error}");
+                    attempts.push(EraseAttempt {
+                        method: candidate,
+                        error: Some(error),
+                        duration_ms,
+                    });
+                }
+            }
+        }
+
+        let verification = match method {
+            Some(method) if !method.is_crypto_erase() => {
+                let verification = verify(device.clone(), self.verify_samples).await;
+                if !verification.passed {
+                    warn!(
+                        "Data remains on {device} after erase at offsets {:?}",
+                        verification.dirty_offsets
+                    );
+                }
+                Some(verification)
+            }
+            _ => None,
+        };
+        let success = method.is_some() && verification.as_ref().is_none_or(|v| v.passed);
+        DeviceEraseReport {
+            device,
+            attempts,
+            method,
+            verification,
+            success,
+        }
+    }
+
+    async fn erase_with(&self, device: &str, method: EraseMethod) -> Result<(), String> {
+        match method {
+            EraseMethod::NvmeSanitizeCryptoErase => {
+                nvme_sanitize(device, NVME_SANACT_CRYPTO_ERASE).await
+            }
+            EraseMethod::NvmeSanitizeBlockErase => {
+                nvme_sanitize(device, NVME_SANACT_BLOCK_ERASE).await
+            }
+            EraseMethod::NvmeFormatCryptoErase => {
+                nvme_format(device.to_owned(), NVME_SES_CRYPTO_ERASE).await
+            }
+            EraseMethod::NvmeFormatUserDataErase => {
+                nvme_format(device.to_owned(), NVME_SES_USER_DATA_ERASE).await
+            }
+            EraseMethod::BlkdiscardSecure => {
+                self.blkdiscard(&["blkdiscard", "--secure", device]).await
+            }
+            EraseMethod::Blkdiscard => self.blkdiscard(&["blkdiscard", device]).await,
+        }
+    }
+
+    /// Discards the device without the command timeout, since this can take hours on large disks.
+    /// The setup deadline still applies.
+    async fn blkdiscard(&self, args: &[&str]) -> Result<(), String> {
+        let commander = self.commander.clone().with_policy(CommandPolicy {
+            timeout: None,
+            ..self.commander.policy.clone()
+        });
+        let output = commander
+            .unchecked_output(args)
+            .await
+            .map_err(|e| e.to_string())?;
+        if output.status.success() {
+            Ok(())
+        } else {
+            Err(String::from_utf8_lossy(&output.stderr).trim().to_owned())
+        }
+    }
+}
+
+/// Runs blocking device I/O, like an ioctl that waits for the device, off the async runtime.
+async fn blocking<T: Send + 'static>(
+    f: impl FnOnce() -> Result<T, String> + Send + 'static,
+) -> Result<T, String> {
+    tokio::task::spawn_blocking(f)
+        .await
+        .map_err(|e| format!("blocking task failed: {e}"))?
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+fn open_device(device: &str) -> Result<File, String> {
+    OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(device)
+        .map_err(|e| format!("failed to open {device}: {e}"))
+}
+
+fn nvme_admin(file: &File, cmd: &mut NvmeAdminCmd) -> Result<(), String> {
+    // SAFETY: cmd is a valid nvme_admin_cmd, and any buffer it points to
+    // is owned by the caller and outlives this call.
+    let rc = unsafe {
+        libc::ioctl(
+            file.as_raw_fd(),
+            NVME_IOCTL_ADMIN_CMD as libc::Ioctl,
+            cmd as *mut NvmeAdminCmd,
+        )
+    };
+    match rc {
+        0 => Ok(()),
+        rc if rc < 0 => Err(format!(
+            "admin command {:#x} failed: {}",
+            cmd.opcode,
+            std::io::Error::last_os_error()
+        )),
+        status => Err(format!(
+            "admin command {:#x} failed with NVMe status {status:#x}",
+            cmd.opcode
+        )),
+    }
+}
+
+fn nvme_namespace_id(file: &File) -> Result<u32, String> {
+    // SAFETY: NVME_IOCTL_ID takes no arguments.
+    let rc = unsafe { libc::ioctl(file.as_raw_fd(), NVME_IOCTL_ID as libc::Ioctl) };
+    if rc < 0 {
+        Err(format!(
+            "not an NVMe namespace: {}",
+            std::io::Error::last_os_error()
+        ))
+    } else {
+        Ok(rc as u32)
+    }
+}
+
+async fn nvme_sanitize(device: &str, sanact: u32) -> Result<(), String> {
+    let file = {
+        let device = device.to_owned();
+        blocking(move || {
+            let file = open_device(&device)?;
+            nvme_namespace_id(&file)?;
+            nvme_admin(
+                &file,
+                &mut NvmeAdminCmd {
+                    opcode: NVME_ADMIN_SANITIZE,
+                    cdw10: sanact,
+                    ..Default::default()
+                },
+            )?;
+            Ok(Arc::new(file))
+        })
+        .await?
+    };
+
+    // Sanitize runs in the background, so poll the status log until it finishes.
+    let start = tokio::time::Instant::now();
+    loop {
+        let log = {
+            let file = file.clone();
+            blocking(move || sanitize_status_log(&file)).await?
+        };
+        let sstat = u16::from_le_bytes([log[2], log[3]]) & 0x7;
+        match sstat {
+            NVME_SANITIZE_STATUS_COMPLETED | NVME_SANITIZE_STATUS_COMPLETED_NO_DEALLOCATE => {
+                return Ok(());
+            }
+            NVME_SANITIZE_STATUS_IN_PROGRESS => {
+                if start.elapsed() > NVME_SANITIZE_TIMEOUT {
+                    return Err(format!(
+                        "sanitize did not complete within {NVME_SANITIZE_TIMEOUT:?}"
+                    ));
+                }
+                let progress = u16::from_le_bytes([log[0], log[1]]);
+                info!(
+                    "Sanitize of {device} in progress: {:.1}%",
+                    f64::from(progress) / 655.36
+                );
+                tokio::time::sleep(Duration::from_secs(5)).await;
+            }
+            sstat => return Err(format!("sanitize failed with status {sstat:#x}")),
+        }
+    }
+}
+
+fn sanitize_status_log(file: &File) -> Result<[u8; 512], String> {
+    let mut log = [0u8; 512];
+    nvme_admin(
+        file,
+        &mut NvmeAdminCmd {
+            opcode: NVME_ADMIN_GET_LOG_PAGE,
+            nsid: 0xFFFF_FFFF,
+            addr: log.as_mut_ptr() as u64,
+            data_len: log.len() as u32,
+            // Number of dwords to read (zero based) in the upper half.
+            cdw10: NVME_LOG_SANITIZE_STATUS | (((log.len() / 4 - 1) as u32) << 16),
+            ..Default::default()
+        },
+    )?;
+    Ok(log)
+}
+
+/// Formats the device, which blocks until the device finishes.
+async fn nvme_format(device: String, ses: u32) -> Result<(), String> {
+    blocking(move || nvme_format_blocking(&device, ses)).await
+}
+
+fn nvme_format_blocking(device: &str, ses: u32) -> Result<(), String> {
+    let file = open_device(device)?;
+    let nsid = nvme_namespace_id(&file)?;
+
+    // Keep the current LBA format, so we only erase and don't reformat.
+    let mut identify = [0u8; 4096];
+    nvme_admin(
+        &file,
+        &mut NvmeAdminCmd {
+            opcode: NVME_ADMIN_IDENTIFY,
+            nsid,
+            addr: identify.as_mut_ptr() as u64,
+            data_len: identify.len() as u32,
+            cdw10: NVME_IDENTIFY_CNS_NAMESPACE,
+            ..Default::default()
+        },
+    )?;
+    let lbaf = u32::from(identify[26] & 0xF);
+
+    nvme_admin(
+        &file,
+        &mut NvmeAdminCmd {
+            opcode: NVME_ADMIN_FORMAT_NVM,
+            nsid,
+            cdw10: lbaf | (ses << 9),
+            timeout_ms: NVME_FORMAT_TIMEOUT.as_millis() as u32,
+            ..Default::default()
+        },
+    )
+}
+
+/// Reads blocks spread evenly across the device, checking that none of them
+/// still contain data. Erased blocks read back as all zeros or all ones,
+/// depending on the device.
+async fn verify(device: String, samples: usize) -> Verification {
+    tokio::task::spawn_blocking(move || verify_blocking(&device, samples))
+        .await
+        .expect("verification panicked")
+}
+
+fn verify_blocking(device: &str, samples: usize) -> Verification {
+    let mut verification = Verification {
+        samples: 0,
+        sample_size: VERIFY_SAMPLE_SIZE,
+        dirty_offsets: Vec::new(),
+        passed: false,
+    };
+    let mut file = match File::open(device) {
+        Ok(file) => file,
+        Err(e) => {
+            warn!("Failed to open {device} for verification: {e}");
+            return verification;
+        }
+    };
+    // Make sure we read from the device, not from stale cached pages.
+    // SAFETY: the file descriptor is valid for the lifetime of file.
+    unsafe { libc::posix_fadvise(file.as_raw_fd(), 0, 0, libc::POSIX_FADV_DONTNEED) };
+    let size = file.seek(SeekFrom::End(0)).unwrap_or(0);
+    let blocks = size / VERIFY_SAMPLE_SIZE as u64;
+    if blocks == 0 || samples == 0 {
+        warn!("Nothing to verify on {device}");
+        return verification;
+    }
+
+    let mut buf = vec![0u8; VERIFY_SAMPLE_SIZE];
+    for i in 0..samples as u64 {
+        // Always include both the first and last blocks.
+        let block = if samples == 1 {
+            0
+        } else {
+            i * (blocks - 1) / (samples as u64 - 1)
+        };
+        let offset = block * VERIFY_SAMPLE_SIZE as u64;
+        let read = file
+            .seek(SeekFrom::Start(offset))
+            .and_then(|_| file.read_exact(&mut buf));
+        if let Err(e) = read {
+            warn!("Failed to read {device} at offset {offset}: {e}");
+            return verification;
+        }
+        verification.samples += 1;
+        let erased = buf.iter().all(|b| *b == 0x00) || buf.iter().all(|b| *b == 0xFF);
+        if !erased {
+            verification.dirty_offsets.push(offset);
+        }
+    }
+    verification.passed = verification.dirty_offsets.is_empty();
+    verification
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{Seek, SeekFrom, Write};
+    use std::time::Duration;
+
+    use tempfile::NamedTempFile;
+
+    use crate::CommandPolicy;
+    use crate::detect::StaticDisks;
+    use crate::erase::{
+        EraseController, EraseMethod, VERIFY_SAMPLE_SIZE, Verification, verify_blocking,
+    };
+    use crate::test::TestEnv;
+
+    fn fake_device(blocks: u64) -> NamedTempFile {
+        let file = NamedTempFile::new().unwrap();
+        file.as_file()
+            .set_len(blocks * VERIFY_SAMPLE_SIZE as u64)
+            .unwrap();
+        file
+    }
+
+    #[test]
+    fn test_verify() {
+        let mut device = fake_device(100);
+        let path = device.path().to_str().unwrap().to_owned();
+        assert_eq!(
+            Verification {
+                samples: 10,
+                sample_size: VERIFY_SAMPLE_SIZE,
+                dirty_offsets: vec![],
+                passed: true,
+            },
+            verify_blocking(&path, 10)
+        );
+
+        // Leave some data behind in the last block.
+        device
+            .seek(SeekFrom::Start(99 * VERIFY_SAMPLE_SIZE as u64 + 7))
+            .unwrap();
+        device.write_all(b"secret").unwrap();
+        assert_eq!(
+            Verification {
+                samples: 10,
+                sample_size: VERIFY_SAMPLE_SIZE,
+                dirty_offsets: vec![99 * VERIFY_SAMPLE_SIZE as u64],
+                passed: false,
+            },
+            verify_blocking(&path, 10)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_erase_falls_back_to_blkdiscard() {
+        let test_env = TestEnv::new();
+        // Slower than the command timeout, which doesn't apply to discards.
+        test_env.mock_script("blkdiscard", "sleep 1");
+        // A regular file doesn't support any of the NVMe ioctls.
+        let device = fake_device(16);
+        let path = device.path().to_str().unwrap().to_owned();
+        let controller = EraseController {
+            commander: test_env.commander.clone().with_policy(CommandPolicy {
+                timeout: Some(Duration::from_millis(200)),
+                ..Default::default()
+            }),
+            disk_detector: StaticDisks(vec![path.clone()]),
+            node_name: None,
+            report_path: None,
+            verify_samples: 4,
+        };
+        let report = controller.erase_device(path).await;
+        assert_eq!(Some(EraseMethod::BlkdiscardSecure), report.method);
+        assert_eq!(5, report.attempts.len());
+        assert!(report.success);
+    }
+}
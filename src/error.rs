@@ -0,0 +1,179 @@
+use std::time::Duration;
+
+use thiserror::Error;
+
+use crate::CloudProvider;
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// Everything that can go wrong while setting up disks.
+///
+/// Failures are grouped into classes with distinct exit codes,
+/// so alerts and pod termination reasons can tell them apart.
+/// See [`Error::exit_code`].
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("No suitable NVMe devices found for cloud provider {0:?}")]
+    NoDisks(CloudProvider),
+
+    #[error("Failed to spawn '{args:?}': {source}")]
+    CommandSpawn {
+        args: Vec<String>,
+        source: std::io::Error,
+    },
+
+    #[error(
+        "Failed to run '{args:?}':
+Exit code: {code:?}
+Stdout:
+{stdout}
+Stderr:
+{stderr}"
+    )]
+    CommandFailed {
+        args: Vec<String>,
+        code: Option<i32>,
+        stdout: String,
+        stderr: String,
+    },
+
+    #[error("Timed out after {timeout:?} running '{args:?}'")]
+    CommandTimedOut {
+        args: Vec<String>,
+        timeout: Duration,
+    },
+
+    #[error("Setup deadline passed before running '{args:?}'")]
+    DeadlineExceeded { args: Vec<String> },
+
+    #[error("{context}: {source}")]
+    Systemd {
+        context: String,
+        // Boxed because zbus errors are much larger than the rest.
+        source: Box<zbus::Error>,
+    },
+
+    #[error("systemd failed to start {unit}: {result}")]
+    SystemdJob { unit: String, result: String },
+
+    #[error("The kubelet didn't become healthy: {0}")]
+    KubeletUnhealthy(String),
+
+    #[error("Restored the original kubelet config after: {0}")]
+    RolledBack(Box<Error>),
+
+    #[error("Failed to restore the original kubelet config: {rollback}, after: {cause}")]
+    RollbackFailed {
+        cause: Box<Error>,
+        rollback: Box<Error>,
+    },
+
+    #[error("Swap isn't in effect: {0}")]
+    SwapNotInEffect(String),
+
+    #[error("Failed to load kubernetes config: {0}")]
+    KubeConfig(#[from] kube::config::InClusterError),
+
+    #[error("{context}: {source}")]
+    Kube {
+        context: String,
+        // Boxed because kube errors are much larger than the rest.
+        source: Box<kube::Error>,
+    },
+
+    #[error("Invalid configuration: {0}")]
+    Config(String),
+
+    #[error("Refusing to continue: {0}")]
+    Refused(String),
+
+    #[error("{context}: {source}")]
+    Io {
+        context: String,
+        source: std::io::Error,
+    },
+
+    #[error("{context}: {source}")]
+    Parse {
+        context: String,
+        source: Box<dyn std::error::Error + Send + Sync>,
+    },
+
+    #[error("Failed to set {key}: wrote '{wanted}', but read back '{actual}'")]
+    TuningNotApplied {
+        key: String,
+        wanted: String,
+        actual: String,
+    },
+
+    #[error("Failed to erase devices {0:?}")]
+    Erase(Vec<String>),
+}
+
+impl Error {
+    /// Exit code for the process when failing with this error.
+    ///
+    /// | Code | Meaning |
+    /// |------|---------|
+    /// | 1    | Any other failure |
+    /// | 2    | Invalid command line arguments (from clap) |
+    /// | 10   | No suitable disks were found |
+    /// | 11   | A command or systemd job failed, timed out, or couldn't be run |
+    /// | 12   | A Kubernetes API call failed |
+    /// | 13   | The configuration is invalid or unsupported |
+    /// | 14   | Refused to touch resources we don't own |
+    /// | 15   | The setup deadline passed |
+    /// | 16   | The kubelet didn't come back after changing its config, and was rolled back, or the rollback failed |
+    /// | 17   | Swap was set up, but the kernel or kubelet isn't using it |
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Error::NoDisks(_) => 10,
+            Error::CommandSpawn { .. }
+            | Error::CommandFailed { .. }
+            | Error::CommandTimedOut { .. }
+            | Error::Systemd { .. }
+            | Error::SystemdJob { .. } => 11,
+            Error::KubeConfig(_) | Error::Kube { .. } => 12,
+            Error::Config(_) => 13,
+            Error::Refused(_) => 14,
+            Error::DeadlineExceeded { .. } => 15,
+            Error::KubeletUnhealthy(_) | Error::RolledBack(_) | Error::RollbackFailed { .. } => 16,
+            Error::SwapNotInEffect(_) => 17,
+            Error::Io { .. }
+            | Error::Parse { .. }
+            | Error::TuningNotApplied { .. }
+            | Error::Erase(_) => 1,
+        }
+    }
+
+    pub fn io(context: impl Into<String>, source: std::io::Error) -> Self {
+        Error::Io {
+            context: context.into(),
+            source,
+        }
+    }
+
+    pub fn parse(
+        context: impl Into<String>,
+        source: impl Into<Box<dyn std::error::Error + Send + Sync>>,
+    ) -> Self {
+        Error::Parse {
+            context: context.into(),
+            source: source.into(),
+        }
+    }
+
+    pub fn systemd(context: impl Into<String>, source: zbus::Error) -> Self {
+        Error::Systemd {
+            context: context.into(),
+            source: Box::new(source),
+        }
+    }
+
+    pub fn kube(context: impl Into<String>, source: kube::Error) -> Self {
+        Error::Kube {
+            context: context.into(),
+            source: Box::new(source),
+        }
+    }
+}
@@ -0,0 +1,886 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::ErrorKind;
+use std::time::Duration;
+
+use k8s_openapi::api::core::v1::Node;
+use kube::{Api, Client};
+use serde_yaml::Value;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tracing::{debug, info, warn};
+
+use crate::error::{Error, Result};
+use crate::swap::KubeletSwapSettings;
+use crate::{CloudProvider, Commander, load_kube_config};
+
+/// Name of the drop-in we write to the kubelet's config directory.
+/// Drop-ins are applied in order, so this sorts after the provider's own.
+pub(crate) const DROP_IN_NAME: &str = "99-ephemeral-storage-swap.conf";
+
+/// Where we point `--config-dir` when the kubelet isn't using one yet.
+const DEFAULT_CONFIG_DIR: &str = "/etc/kubernetes/kubelet.conf.d";
+
+/// The first kubelet version where `--config-dir` is enabled by default.
+const CONFIG_DIR_MIN_VERSION: (u32, u32) = (1, 30);
+
+/// Azure's kubelet config file, which it doesn't use by default.
+const AZURE_CONFIG_PATH: &str = "/var/lib/kubelet/config.yaml";
+/// Azure's kubelet unit references `KUBELET_CONFIG_FILE_FLAGS`, but doesn't set it.
+const AZURE_SYSTEMD_DROP_IN_PATH: &str =
+    "/etc/systemd/system/kubelet.service.d/99-enable-swap.conf";
+
+const GCP_CONFIG_PATH: &str = "/home/kubernetes/kubelet-config.yaml";
+
+/// The config dir nodeadm starts the kubelet with on Amazon Linux 2023.
+const AL2023_CONFIG_DIR: &str = "/etc/kubernetes/kubelet/config.json.d";
+
+/// The kubelet's default healthz endpoint. We run in the host network namespace.
+pub(crate) const HEALTHZ_ADDR: &str = "127.0.0.1:10248";
+const HEALTH_POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Changes settings in the kubelet config of the host.
+///
+/// Where the kubelet supports it, settings are written to a drop-in in its
+/// `--config-dir`, leaving the provider's config file untouched.
+/// Otherwise, they are merged into the main config file.
+/// Either way, the kubelet must be restarted for them to take effect.
+pub(crate) struct KubeletConfig<'a> {
+    pub(crate) commander: &'a Commander,
+    pub(crate) cloud_provider: CloudProvider,
+}
+
+impl KubeletConfig<'_> {
+    /// Applies the top level settings to the kubelet config.
+    /// Returns a backup of the files that were changed, so they can be restored.
+    pub(crate) async fn apply(&self, settings: &BTreeMap<String, Value>) -> Result<KubeletBackup> {
+        let mut backup = KubeletBackup::default();
+        let kubelet_args = self.running_kubelet_args()?;
+        if let Some(config_dir) = kubelet_args
+            .as_deref()
+            .and_then(|args| flag_value(args, "--config-dir"))
+        {
+            info!("Kubelet is already using config dir {config_dir}");
+            self.write_drop_in(&mut backup, config_dir, settings)?;
+            return Ok(backup);
+        }
+
+        let binary = kubelet_args
+            .as_ref()
+            .and_then(|args| args.first())
+            .map_or("kubelet", |binary| binary.as_str());
+        let supports_config_dir = self
+            .kubelet_version(binary)
+            .await?
+            .is_some_and(|version| version >= CONFIG_DIR_MIN_VERSION);
+
+        match self.cloud_provider {
+            CloudProvider::Azure if supports_config_dir => {
+                // We control the kubelet's config flags here,
+                // so we can add --config-dir ourselves.
+                self.ensure_config_file(&mut backup, AZURE_CONFIG_PATH)?;
+                self.write_drop_in(&mut backup, DEFAULT_CONFIG_DIR, settings)?;
+                self.write_azure_systemd_drop_in(
+                    &mut backup,
+                    &format!("--config {AZURE_CONFIG_PATH} --config-dir {DEFAULT_CONFIG_DIR}"),
+                )?;
+            }
+            CloudProvider::Azure => {
+                self.merge_into_config_file(&mut backup, AZURE_CONFIG_PATH, settings)?;
+                self.write_azure_systemd_drop_in(
+                    &mut backup,
+                    &format!("--config {AZURE_CONFIG_PATH}"),
+                )?;
+            }
+            CloudProvider::Aws if self.is_al2023()? => {
+                // nodeadm regenerates its own config on boot, but leaves other drop-ins alone.
+                self.write_drop_in(&mut backup, AL2023_CONFIG_DIR, settings)?;
+            }
+            CloudProvider::Aws => {
+                return Err(Error::Config(
+                    "Changing the kubelet config on AWS is only supported on Amazon Linux 2023. \
+                     Use --bottlerocket-enable-swap on Bottlerocket."
+                        .to_owned(),
+                ));
+            }
+            CloudProvider::Gcp => {
+                let path = kubelet_args
+                    .as_deref()
+                    .and_then(|args| flag_value(args, "--config"))
+                    .unwrap_or(GCP_CONFIG_PATH);
+                self.merge_into_config_file(&mut backup, path, settings)?;
+            }
+            _ => {
+                return Err(Error::Config(format!(
+                    "Changing the kubelet config is not supported for cloud provider: {:?}",
+                    self.cloud_provider
+                )));
+            }
+        }
+        Ok(backup)
+    }
+
+    fn is_al2023(&self) -> Result<bool> {
+        let path = self.commander.host_path("/etc/os-release");
+        let os_release = match fs::read_to_string(&path) {
+            Ok(contents) => parse_os_release(&contents),
+            Err(e) if e.kind() == ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => return Err(Error::io(format!("Failed to read {path}"), e)),
+        };
+        debug!("Host os-release: {os_release:?}");
+        Ok(os_release.get("ID").map(String::as_str) == Some("amzn")
+            && os_release.get("VERSION_ID").map(String::as_str) == Some("2023"))
+    }
+
+    /// Finds the command line of the running kubelet, if any.
+    fn running_kubelet_args(&self) -> Result<Option<Vec<String>>> {
+        let proc_path = self.commander.host_path("/proc");
+        let entries = fs::read_dir(&proc_path)
+            .map_err(|e| Error::io(format!("Failed to list {proc_path}"), e))?;
+        for entry in entries.flatten() {
+            let path = entry.path();
+            // Processes can exit while we look at them, so skip any we can't read.
+            let Ok(comm) = fs::read_to_string(path.join("comm")) else {
+                continue;
+            };
+            if comm.trim() != "kubelet" {
+                continue;
+            }
+            let Ok(cmdline) = fs::read(path.join("cmdline")) else {
+                continue;
+            };
+            let args: Vec<String> = cmdline
+                .split(|b| *b == 0)
+                .filter(|arg| !arg.is_empty())
+                .map(|arg| String::from_utf8_lossy(arg).into_owned())
+                .collect();
+            debug!("Found kubelet running with args {args:?}");
+            return Ok(Some(args));
+        }
+        Ok(None)
+    }
+
+    /// Returns the (major, minor) version of the kubelet binary on the host,
+    /// if it can be found.
+    async fn kubelet_version(&self, binary: &str) -> Result<Option<(u32, u32)>> {
+        let output = match self
+            .commander
+            .on_host()
+            .check_output(&[binary, "--version"])
+            .await
+        {
+            Ok(output) => output,
+            Err(e) => {
+                warn!("Failed to get kubelet version, assuming it doesn't support drop-ins: {e}");
+                return Ok(None);
+            }
+        };
+        let output = String::from_utf8_lossy(&output.stdout);
+        let version = parse_version(&output);
+        info!("Kubelet version is {version:?}");
+        Ok(version)
+    }
+
+    fn write_drop_in(
+        &self,
+        backup: &mut KubeletBackup,
+        config_dir: &str,
+        settings: &BTreeMap<String, Value>,
+    ) -> Result<()> {
+        let mut drop_in = BTreeMap::new();
+        add_type_info(&mut drop_in);
+        drop_in.extend(settings.clone());
+        let path = self
+            .commander
+            .host_path(&format!("{config_dir}/{DROP_IN_NAME}"));
+        info!("Writing kubelet config drop-in {path}");
+        self.write(backup, &path, &serde_yaml::to_string(&drop_in).unwrap())
+    }
+
+    /// Creates a config file with only the type information if there isn't one.
+    fn ensure_config_file(&self, backup: &mut KubeletBackup, path: &str) -> Result<()> {
+        let path = self.commander.host_path(path);
+        if fs::exists(&path).map_err(|e| Error::io(format!("Failed to check for {path}"), e))? {
+            return Ok(());
+        }
+        let mut config = BTreeMap::new();
+        add_type_info(&mut config);
+        self.write(backup, &path, &serde_yaml::to_string(&config).unwrap())
+    }
+
+    /// Merges the settings into the main config file,
+    /// for kubelets that don't support drop-ins.
+    ///
+    /// This loses comments and key order in the file.
+    fn merge_into_config_file(
+        &self,
+        backup: &mut KubeletBackup,
+        path: &str,
+        settings: &BTreeMap<String, Value>,
+    ) -> Result<()> {
+        let path = self.commander.host_path(path);
+        info!("Merging settings into kubelet config {path}");
+        // Read existing configuration, if any.
+        let mut kubelet_config: BTreeMap<String, Value> = match fs::read(&path) {
+            Ok(data) => serde_yaml::from_slice(&data)
+                .map_err(|e| Error::parse(format!("Failed to parse kubelet config {path}"), e))?,
+            Err(e) if e.kind() == ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => {
+                return Err(Error::io(
+                    format!("Failed to read kubelet config {path}"),
+                    e,
+                ));
+            }
+        };
+        // Ensure we have the type information, in case we're making a new file.
+        add_type_info(&mut kubelet_config);
+        // Merge nested settings, so we don't drop other eviction thresholds or reservations.
+        for (key, value) in settings.clone() {
+            match kubelet_config.get_mut(&key) {
+                Some(existing) => merge_value(existing, value),
+                None => {
+                    kubelet_config.insert(key, value);
+                }
+            }
+        }
+        self.write(
+            backup,
+            &path,
+            &serde_yaml::to_string(&kubelet_config).unwrap(),
+        )
+    }
+
+    /// Writes a file on the host, backing up what was there first.
+    fn write(&self, backup: &mut KubeletBackup, path: &str, contents: &str) -> Result<()> {
+        backup.snapshot(path)?;
+        self.commander.write_file(path, contents)
+    }
+
+    fn write_azure_systemd_drop_in(&self, backup: &mut KubeletBackup, flags: &str) -> Result<()> {
+        self.write(
+            backup,
+            &self.commander.host_path(AZURE_SYSTEMD_DROP_IN_PATH),
+            &format!("[Service]\nEnvironment=\"KUBELET_CONFIG_FILE_FLAGS={flags}\"\n"),
+        )
+    }
+}
+
+/// The contents of kubelet config files and systemd drop-ins before we changed them.
+#[derive(Default, Debug)]
+pub(crate) struct KubeletBackup {
+    // None if the file didn't exist.
+    files: Vec<(String, Option<String>)>,
+}
+
+impl KubeletBackup {
+    fn snapshot(&mut self, path: &str) -> Result<()> {
+        // Only the first snapshot has the original contents.
+        if self.files.iter().any(|(backed_up, _)| backed_up == path) {
+            return Ok(());
+        }
+        let contents = match fs::read_to_string(path) {
+            Ok(contents) => Some(contents),
+            Err(e) if e.kind() == ErrorKind::NotFound => None,
+            Err(e) => return Err(Error::io(format!("Failed to back up {path}"), e)),
+        };
+        self.files.push((path.to_owned(), contents));
+        Ok(())
+    }
+
+    /// Puts the files back how they were, removing any we created.
+    pub(crate) fn restore(&self, commander: &Commander) -> Result<()> {
+        for (path, contents) in &self.files {
+            info!("Restoring {path}");
+            match contents {
+                Some(contents) => commander.write_file(path, contents)?,
+                None => commander.remove_file(path)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Parses the `KEY=value` lines of an os-release file.
+fn parse_os_release(contents: &str) -> BTreeMap<String, String> {
+    contents
+        .lines()
+        .filter(|line| !line.trim_start().starts_with('#'))
+        .filter_map(|line| line.split_once('='))
+        .map(|(key, value)| {
+            let value = value.trim().trim_matches(|c| c == '"' || c == '\'');
+            (key.trim().to_owned(), value.to_owned())
+        })
+        .collect()
+}
+
+/// Waits for the kubelet to report healthy, and for the node to be Ready if we know its name.
+pub(crate) async fn wait_until_healthy(
+    commander: &Commander,
+    healthz_addr: &str,
+    node_name: Option<&str>,
+    timeout: Duration,
+) -> Result<()> {
+    if !commander.should_perform("wait for the kubelet to become healthy".to_owned()) {
+        return Ok(());
+    }
+    let node_api: Option<Api<Node>> = match node_name {
+        Some(_) => {
+            let client = Client::try_from(load_kube_config().await?)
+                .map_err(|e| Error::kube("Failed to create kubernetes client", e))?;
+            Some(Api::all(client))
+        }
+        None => None,
+    };
+    info!("Waiting up to {timeout:?} for the kubelet to become healthy");
+    let start = tokio::time::Instant::now();
+    loop {
+        let problem = match check_healthz(healthz_addr).await {
+            Err(problem) => problem,
+            Ok(()) => match (&node_api, node_name) {
+                (Some(node_api), Some(node_name)) => match node_ready(node_api, node_name).await {
+                    Ok(()) => return Ok(()),
+                    Err(problem) => problem,
+                },
+                _ => return Ok(()),
+            },
+        };
+        if start.elapsed() >= timeout {
+            return Err(Error::KubeletUnhealthy(problem));
+        }
+        debug!("Kubelet not healthy yet: {problem}");
+        tokio::time::sleep(HEALTH_POLL_INTERVAL).await;
+    }
+}
+
+/// Checks the kubelet's healthz endpoint, returning what was wrong if it isn't healthy.
+async fn check_healthz(addr: &str) -> std::result::Result<(), String> {
+    let request = async {
+        let mut stream = TcpStream::connect(addr).await?;
+        stream
+            .write_all(b"GET /healthz HTTP/1.0\r\nHost: localhost\r\n\r\n")
+            .await?;
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await?;
+        Ok::<_, std::io::Error>(response)
+    };
+    let response = tokio::time::timeout(HEALTH_POLL_INTERVAL, request)
+        .await
+        .map_err(|_| format!("timed out requesting {addr}/healthz"))?
+        .map_err(|e| format!("failed to request {addr}/healthz: {e}"))?;
+    let status = response.lines().next().unwrap_or_default();
+    let body = response.split("\r\n\r\n").nth(1).unwrap_or_default();
+    if status.split_whitespace().nth(1) == Some("200") && body.trim() == "ok" {
+        Ok(())
+    } else {
+        Err(format!("healthz returned '{status}': {}", body.trim()))
+    }
+}
+
+async fn node_ready(node_api: &Api<Node>, node_name: &str) -> std::result::Result<(), String> {
+    let node = node_api
+        .get(node_name)
+        .await
+        .map_err(|e| format!("failed to get node {node_name}: {e}"))?;
+    let ready = node
+        .status
+        .and_then(|status| status.conditions)
+        .unwrap_or_default()
+        .into_iter()
+        .find(|condition| condition.type_ == "Ready");
+    match ready {
+        Some(condition) if condition.status == "True" => Ok(()),
+        Some(condition) => Err(format!(
+            "node {node_name} is not Ready: {}",
+            condition.message.unwrap_or(condition.status)
+        )),
+        None => Err(format!("node {node_name} has no Ready condition")),
+    }
+}
+
+/// Reads the config the kubelet is actually running with from its `configz` endpoint,
+/// through the API server's node proxy.
+///
+/// Returns `None` if we aren't allowed to `get` the `nodes/proxy` resource.
+pub(crate) async fn running_config(node_name: &str) -> Result<Option<serde_json::Value>> {
+    let client = Client::try_from(load_kube_config().await?)
+        .map_err(|e| Error::kube("Failed to create kubernetes client", e))?;
+    let request = http::Request::get(format!("/api/v1/nodes/{node_name}/proxy/configz"))
+        .body(Vec::new())
+        .expect("valid request");
+    let mut configz: serde_json::Value = match client.request(request).await {
+        Ok(configz) => configz,
+        Err(kube::Error::Api(response)) if response.code == 403 => return Ok(None),
+        Err(e) => {
+            return Err(Error::kube(
+                format!("Failed to get kubelet config of node {node_name}"),
+                e,
+            ));
+        }
+    };
+    Ok(Some(configz["kubeletconfig"].take()))
+}
+
+/// The kubelet config for enabling swap with these settings.
+pub(crate) fn swap_settings(settings: &KubeletSwapSettings) -> BTreeMap<String, Value> {
+    let mapping = |entries: &[(&str, &str)]| {
+        Value::Mapping(
+            entries
+                .iter()
+                .map(|(key, value)| (Value::from(*key), Value::from(*value)))
+                .collect(),
+        )
+    };
+    let mut config = BTreeMap::from([
+        ("failSwapOn".to_owned(), Value::Bool(false)),
+        (
+            "memorySwap".to_owned(),
+            mapping(&[("swapBehavior", settings.swap_behavior.as_str())]),
+        ),
+    ]);
+    let optional = [
+        (
+            "evictionHard",
+            "memory.available",
+            &settings.eviction_hard_memory,
+        ),
+        (
+            "evictionSoft",
+            "memory.available",
+            &settings.eviction_soft_memory,
+        ),
+        (
+            "evictionSoftGracePeriod",
+            "memory.available",
+            &settings.eviction_soft_grace_period,
+        ),
+        ("systemReserved", "memory", &settings.system_reserved_memory),
+        ("kubeReserved", "memory", &settings.kube_reserved_memory),
+    ];
+    for (field, key, value) in optional {
+        if let Some(value) = value {
+            config.insert(field.to_owned(), mapping(&[(key, value)]));
+        }
+    }
+    config
+}
+
+/// Merges `overlay` into `base`, keeping keys of nested mappings that `overlay` doesn't set.
+fn merge_value(base: &mut Value, overlay: Value) {
+    match (base, overlay) {
+        (Value::Mapping(base), Value::Mapping(overlay)) => {
+            for (key, value) in overlay {
+                match base.get_mut(&key) {
+                    Some(existing) => merge_value(existing, value),
+                    None => {
+                        base.insert(key, value);
+                    }
+                }
+            }
+        }
+        (base, overlay) => *base = overlay,
+    }
+}
+
+fn add_type_info(config: &mut BTreeMap<String, Value>) {
+    config
+        .entry("kind".to_owned())
+        .or_insert(Value::String("KubeletConfiguration".to_owned()));
+    config
+        .entry("apiVersion".to_owned())
+        .or_insert(Value::String("kubelet.config.k8s.io/v1beta1".to_owned()));
+}
+
+/// Finds the value of a flag, passed as either `--flag value` or `--flag=value`.
+fn flag_value<'a>(args: &'a [String], flag: &str) -> Option<&'a str> {
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if arg == flag {
+            return args.next().map(|value| value.as_str());
+        }
+        if let Some(value) = arg
+            .strip_prefix(flag)
+            .and_then(|rest| rest.strip_prefix('='))
+        {
+            return Some(value);
+        }
+    }
+    None
+}
+
+/// Parses output like `Kubernetes v1.31.2-eks-1234` into (1, 31).
+fn parse_version(output: &str) -> Option<(u32, u32)> {
+    let version = output
+        .split_whitespace()
+        .find_map(|word| word.strip_prefix('v'))?;
+    let mut parts = version.split('.');
+    let major = parts.next()?.parse().ok()?;
+    let minor = parts
+        .next()?
+        .chars()
+        .take_while(|c| c.is_ascii_digit())
+        .collect::<String>()
+        .parse()
+        .ok()?;
+    Some((major, minor))
+}
+
+#[cfg(test)]
+mod test {
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::test::TestEnv;
+
+    #[test]
+    fn test_parse_version() {
+        assert_eq!(parse_version("Kubernetes v1.31.2\n"), Some((1, 31)));
+        assert_eq!(
+            parse_version("Kubernetes v1.29.10-eks-59bf375"),
+            Some((1, 29))
+        );
+        assert_eq!(parse_version("Kubernetes v1.30+abc"), Some((1, 30)));
+        assert_eq!(parse_version("kubelet: command not found"), None);
+    }
+
+    #[test]
+    fn test_parse_os_release() {
+        let os_release = parse_os_release(
+            r#"NAME="Amazon Linux"
+VERSION="2023"
+ID="amzn"
+ID_LIKE="fedora"
+# A comment
+VERSION_ID="2023"
+PLATFORM_ID="platform:al2023"
+"#,
+        );
+        assert_eq!(os_release["ID"], "amzn");
+        assert_eq!(os_release["VERSION_ID"], "2023");
+        assert_eq!(os_release["NAME"], "Amazon Linux");
+        assert_eq!(os_release.len(), 6);
+    }
+
+    #[test]
+    fn test_flag_value() {
+        let args: Vec<String> = [
+            "/usr/bin/kubelet",
+            "--config=/etc/kubernetes/kubelet/config.json",
+            "--config-dir",
+            "/etc/kubernetes/kubelet/config.json.d",
+        ]
+        .into_iter()
+        .map(str::to_owned)
+        .collect();
+        assert_eq!(
+            flag_value(&args, "--config"),
+            Some("/etc/kubernetes/kubelet/config.json")
+        );
+        assert_eq!(
+            flag_value(&args, "--config-dir"),
+            Some("/etc/kubernetes/kubelet/config.json.d")
+        );
+        assert_eq!(flag_value(&args, "--kubeconfig"), None);
+    }
+
+    #[test]
+    fn test_merge_value() {
+        let mut config: Value = serde_yaml::from_str(
+            "evictionHard:
+  memory.available: 100Mi
+  nodefs.available: 10%
+failSwapOn: true
+",
+        )
+        .unwrap();
+        let settings = swap_settings(&KubeletSwapSettings {
+            eviction_hard_memory: Some("1Gi".to_owned()),
+            ..Default::default()
+        });
+        merge_value(&mut config, serde_yaml::to_value(&settings).unwrap());
+        assert_eq!(
+            serde_yaml::to_string(&config).unwrap(),
+            "evictionHard:
+  memory.available: 1Gi
+  nodefs.available: 10%
+failSwapOn: false
+memorySwap:
+  swapBehavior: LimitedSwap
+"
+        );
+    }
+
+    const DROP_IN: &str = "apiVersion: kubelet.config.k8s.io/v1beta1
+failSwapOn: false
+kind: KubeletConfiguration
+memorySwap:
+  swapBehavior: LimitedSwap
+";
+
+    /// Sets up a fake host root, with a kubelet of this version running with the arguments, if any.
+    fn fake_host(
+        test_env: &TestEnv,
+        kubelet_args: Option<&[&str]>,
+        os_release: &str,
+        version: &str,
+    ) -> Commander {
+        let root = test_env.temp_dir.path().join("host");
+        fs::create_dir_all(root.join("proc")).unwrap();
+        if let Some(args) = kubelet_args {
+            let process = root.join("proc/1234");
+            fs::create_dir_all(&process).unwrap();
+            fs::write(process.join("comm"), "kubelet\n").unwrap();
+            fs::write(process.join("cmdline"), args.join("\0") + "\0").unwrap();
+        }
+        fs::create_dir_all(root.join("etc")).unwrap();
+        fs::write(root.join("etc/os-release"), os_release).unwrap();
+        // `kubelet --version` is run on the host through chroot.
+        test_env.mock("chroot", 0, version);
+        Commander {
+            host_root: Some(root.to_str().unwrap().to_owned()),
+            ..test_env.commander.clone()
+        }
+    }
+
+    fn read_host(commander: &Commander, path: &str) -> String {
+        fs::read_to_string(commander.host_path(path)).unwrap()
+    }
+
+    #[test]
+    fn test_drop_in_contents() {
+        let test_env = TestEnv::new();
+        let commander = fake_host(&test_env, None, "", "");
+        let config = KubeletConfig {
+            commander: &commander,
+            cloud_provider: CloudProvider::Generic,
+        };
+        let mut backup = KubeletBackup::default();
+        config
+            .write_drop_in(
+                &mut backup,
+                "/etc/kubernetes/kubelet.conf.d",
+                &swap_settings(&KubeletSwapSettings::default()),
+            )
+            .unwrap();
+        assert_eq!(
+            read_host(
+                &commander,
+                "/etc/kubernetes/kubelet.conf.d/99-ephemeral-storage-swap.conf"
+            ),
+            DROP_IN
+        );
+    }
+
+    #[tokio::test]
+    async fn test_apply_azure() {
+        let test_env = TestEnv::new();
+        let commander = fake_host(&test_env, None, "", "Kubernetes v1.31.2");
+        let backup = KubeletConfig {
+            commander: &commander,
+            cloud_provider: CloudProvider::Azure,
+        }
+        .apply(&swap_settings(&KubeletSwapSettings::default()))
+        .await
+        .unwrap();
+        assert_eq!(
+            read_host(&commander, "/var/lib/kubelet/config.yaml"),
+            "apiVersion: kubelet.config.k8s.io/v1beta1
+kind: KubeletConfiguration
+"
+        );
+        assert_eq!(
+            read_host(
+                &commander,
+                "/etc/kubernetes/kubelet.conf.d/99-ephemeral-storage-swap.conf"
+            ),
+            DROP_IN
+        );
+        assert_eq!(
+            read_host(
+                &commander,
+                "/etc/systemd/system/kubelet.service.d/99-enable-swap.conf"
+            ),
+            "[Service]
+Environment=\"KUBELET_CONFIG_FILE_FLAGS=--config /var/lib/kubelet/config.yaml --config-dir /etc/kubernetes/kubelet.conf.d\"
+"
+        );
+
+        // We created all of them, so restoring removes them.
+        backup.restore(&commander).unwrap();
+        assert!(!fs::exists(commander.host_path("/var/lib/kubelet/config.yaml")).unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_apply_azure_without_config_dir() {
+        let test_env = TestEnv::new();
+        let commander = fake_host(&test_env, None, "", "Kubernetes v1.29.10");
+        fs::create_dir_all(commander.host_path("/var/lib/kubelet")).unwrap();
+        fs::write(
+            commander.host_path("/var/lib/kubelet/config.yaml"),
+            "failSwapOn: true\nmaxPods: 110\n",
+        )
+        .unwrap();
+        KubeletConfig {
+            commander: &commander,
+            cloud_provider: CloudProvider::Azure,
+        }
+        .apply(&swap_settings(&KubeletSwapSettings::default()))
+        .await
+        .unwrap();
+        assert_eq!(
+            read_host(&commander, "/var/lib/kubelet/config.yaml"),
+            "apiVersion: kubelet.config.k8s.io/v1beta1
+failSwapOn: false
+kind: KubeletConfiguration
+maxPods: 110
+memorySwap:
+  swapBehavior: LimitedSwap
+"
+        );
+        assert_eq!(
+            read_host(
+                &commander,
+                "/etc/systemd/system/kubelet.service.d/99-enable-swap.conf"
+            ),
+            "[Service]
+Environment=\"KUBELET_CONFIG_FILE_FLAGS=--config /var/lib/kubelet/config.yaml\"
+"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_apply_gcp() {
+        let test_env = TestEnv::new();
+        let commander = fake_host(
+            &test_env,
+            Some(&[
+                "/home/kubernetes/bin/kubelet",
+                "--config=/etc/kubelet/config.yaml",
+            ]),
+            "",
+            "Kubernetes v1.29.10-gke.1",
+        );
+        fs::create_dir_all(commander.host_path("/etc/kubelet")).unwrap();
+        fs::write(
+            commander.host_path("/etc/kubelet/config.yaml"),
+            "kind: KubeletConfiguration\nevictionHard:\n  nodefs.available: 10%\n",
+        )
+        .unwrap();
+        KubeletConfig {
+            commander: &commander,
+            cloud_provider: CloudProvider::Gcp,
+        }
+        .apply(&swap_settings(&KubeletSwapSettings {
+            eviction_hard_memory: Some("1Gi".to_owned()),
+            ..Default::default()
+        }))
+        .await
+        .unwrap();
+        // The config file the kubelet was started with, not the default.
+        assert_eq!(
+            read_host(&commander, "/etc/kubelet/config.yaml"),
+            "apiVersion: kubelet.config.k8s.io/v1beta1
+evictionHard:
+  nodefs.available: 10%
+  memory.available: 1Gi
+failSwapOn: false
+kind: KubeletConfiguration
+memorySwap:
+  swapBehavior: LimitedSwap
+"
+        );
+        assert!(!fs::exists(commander.host_path(GCP_CONFIG_PATH)).unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_apply_al2023() {
+        let test_env = TestEnv::new();
+        let al2023 = "ID=\"amzn\"\nVERSION_ID=\"2023\"\n";
+        let commander = fake_host(&test_env, None, al2023, "Kubernetes v1.31.2-eks-1");
+        KubeletConfig {
+            commander: &commander,
+            cloud_provider: CloudProvider::Aws,
+        }
+        .apply(&swap_settings(&KubeletSwapSettings::default()))
+        .await
+        .unwrap();
+        assert_eq!(
+            read_host(
+                &commander,
+                "/etc/kubernetes/kubelet/config.json.d/99-ephemeral-storage-swap.conf"
+            ),
+            DROP_IN
+        );
+
+        // Anything else on AWS isn't supported.
+        let commander = fake_host(
+            &test_env,
+            None,
+            "ID=\"amzn\"\nVERSION_ID=\"2\"\n",
+            "Kubernetes v1.31.2-eks-1",
+        );
+        let result = KubeletConfig {
+            commander: &commander,
+            cloud_provider: CloudProvider::Aws,
+        }
+        .apply(&swap_settings(&KubeletSwapSettings::default()))
+        .await;
+        assert!(matches!(result, Err(Error::Config(_))));
+    }
+
+    #[test]
+    fn test_backup_restore() {
+        let test_env = TestEnv::new();
+        let dir = test_env.temp_dir.path();
+        let existing = dir.join("config.yaml").to_str().unwrap().to_owned();
+        let created = dir.join("drop-in.conf").to_str().unwrap().to_owned();
+        fs::write(&existing, "original").unwrap();
+
+        let mut backup = KubeletBackup::default();
+        backup.snapshot(&existing).unwrap();
+        backup.snapshot(&created).unwrap();
+        fs::write(&existing, "changed").unwrap();
+        // Later snapshots of the same file don't replace the original.
+        backup.snapshot(&existing).unwrap();
+        fs::write(&created, "new").unwrap();
+
+        backup.restore(&test_env.commander).unwrap();
+        assert_eq!(fs::read_to_string(&existing).unwrap(), "original");
+        assert!(!fs::exists(&created).unwrap());
+    }
+
+    /// Serves a single HTTP response to each connection.
+    async fn serve_healthz(response: &'static str) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut request = [0; 1024];
+                let _ = stream.read(&mut request).await.unwrap();
+                stream.write_all(response.as_bytes()).await.unwrap();
+            }
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn test_check_healthz() {
+        let healthy = serve_healthz("HTTP/1.1 200 OK\r\n\r\nok").await;
+        assert_eq!(check_healthz(&healthy).await, Ok(()));
+
+        let unhealthy =
+            serve_healthz("HTTP/1.1 500 Internal Server Error\r\n\r\n[-]syncloop failed").await;
+        assert_eq!(
+            check_healthz(&unhealthy).await,
+            Err(
+                "healthz returned 'HTTP/1.1 500 Internal Server Error': [-]syncloop failed"
+                    .to_owned()
+            )
+        );
+
+        let test_env = TestEnv::new();
+        let result =
+            wait_until_healthy(&test_env.commander, &unhealthy, None, Duration::ZERO).await;
+        assert!(matches!(result, Err(Error::KubeletUnhealthy(_))));
+    }
+}
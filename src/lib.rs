@@ -1,13 +1,33 @@
 use std::collections::HashMap;
-use std::process::{Command, Output};
-use std::time::Duration;
+use std::fmt;
+use std::os::unix::process::ExitStatusExt;
+use std::process::{ExitStatus, Output, Stdio};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
 use clap::ValueEnum;
+use serde::Serialize;
+use tokio::process::Command;
+use tracing::{info, warn};
 
+use crate::error::{Error, Result};
+use crate::transcript::Transcript;
+
+pub mod combined;
+pub mod crypt;
 pub mod detect;
+pub mod erase;
+pub mod error;
+mod kubelet;
 pub mod lvm;
+pub mod partition;
 mod remove_taint;
 pub mod swap;
+mod systemd;
+pub mod teardown;
+pub mod transcript;
+pub mod tuning;
+pub mod zram;
 
 #[derive(ValueEnum, Clone, Copy, Debug)]
 pub enum CloudProvider {
@@ -17,53 +37,393 @@ pub enum CloudProvider {
     Generic,
 }
 
+/// Something that changes the system, recorded instead of done in a dry run.
+#[derive(Serialize, Clone, Debug, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum PlanStep {
+    /// Run a command.
+    Command { args: Vec<String> },
+    /// Write a file, replacing it if it exists.
+    WriteFile { path: String, contents: String },
+    /// Anything else, like a Kubernetes API call.
+    Action { description: String },
+}
+
+impl fmt::Display for PlanStep {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PlanStep::Command { args } => {
+                let args: Vec<String> = args
+                    .iter()
+                    .map(|arg| {
+                        if arg.is_empty() || arg.contains(|c: char| c.is_whitespace() || c == '\'')
+                        {
+                            format!("'{}'", arg.replace('\'', r"'\''"))
+                        } else {
+                            arg.clone()
+                        }
+                    })
+                    .collect();
+                write!(f, "run: {}", args.join(" "))
+            }
+            PlanStep::WriteFile { path, contents } => {
+                write!(f, "write {path}:\n{}", contents.trim_end())
+            }
+            PlanStep::Action { description } => write!(f, "{description}"),
+        }
+    }
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+pub enum PlanFormat {
+    Text,
+    Json,
+}
+
+/// Limits on how long commands may run, and how failures are retried.
+#[derive(Clone, Debug, Default)]
+pub struct CommandPolicy {
+    /// Commands running longer than this are killed.
+    pub timeout: Option<Duration>,
+    /// No commands are started after this, and running ones are killed when it passes.
+    pub deadline: Option<Instant>,
+    /// How many times to retry commands that are safe to run again.
+    pub retries: u32,
+    /// How long to wait before the first retry. This doubles after each retry.
+    pub retry_backoff: Duration,
+}
+
+/// How to run commands on the host, rather than in our container.
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq)]
+pub enum HostExecutor {
+    /// chroot into the host's root filesystem, mounted at /host.
+    ///
+    /// This only changes the root directory, so commands still run
+    /// in our container's mount, PID, and cgroup namespaces.
+    #[default]
+    Chroot,
+    /// Enter the namespaces of the host's PID 1 with nsenter, which uses setns.
+    ///
+    /// This requires the pod to use the host's PID namespace (hostPID).
+    Nsenter,
+}
+
+impl HostExecutor {
+    /// Arguments to put in front of a command to run it on the host.
+    fn prefix(&self) -> &'static [&'static str] {
+        match self {
+            HostExecutor::Chroot => &["chroot", "/host"],
+            HostExecutor::Nsenter => &[
+                "nsenter", "--target", "1", "--mount", "--uts", "--ipc", "--net", "--pid",
+                "--cgroup", "--",
+            ],
+        }
+    }
+
+    /// Where a path on the host can be found from our container.
+    pub fn host_path(&self, path: &str) -> String {
+        match self {
+            HostExecutor::Chroot => format!("/host{path}"),
+            HostExecutor::Nsenter => format!("/proc/1/root{path}"),
+        }
+    }
+}
+
 #[derive(Clone, Default)]
 pub struct Commander {
     // Environment variables to set on child processes.
     // This is mostly useful in testing to point at mocks.
     pub(crate) envs: HashMap<String, String>,
+    // If set, this is a dry run, and changes are recorded here instead of being made.
+    pub(crate) plan: Option<Arc<Mutex<Vec<PlanStep>>>>,
+    pub(crate) policy: CommandPolicy,
+    // If set, every command we run is recorded here.
+    pub(crate) transcript: Option<Arc<Transcript>>,
+    pub(crate) host_executor: HostExecutor,
+    // If set, commands are run on the host using the host executor.
+    pub(crate) on_host: bool,
+    // If set, host files are found under this directory instead of where the host executor has them.
+    // This is only useful in testing.
+    pub(crate) host_root: Option<String>,
 }
 
 impl Commander {
-    fn check_output(&self, args: &[&str]) -> Output {
-        let failure_msg = format!("Failed to run '{args:?}'");
-        let output = self.unchecked_output(args);
-        let rc = output.status.code();
-        if rc.unwrap() != 0 {
-            panic!(
-                "{failure_msg}:
-Exit code: {rc:?}
-Stdout:
-{}
-Stderr:
-{}",
-                String::from_utf8_lossy(&output.stdout),
-                String::from_utf8_lossy(&output.stderr),
-            );
+    /// Creates a Commander that records changes instead of making them.
+    /// Read-only commands still run.
+    pub fn dry_run() -> Self {
+        Commander {
+            plan: Some(Arc::default()),
+            ..Default::default()
+        }
+    }
+
+    pub fn with_policy(self, policy: CommandPolicy) -> Self {
+        Commander { policy, ..self }
+    }
+
+    pub fn with_transcript(self, transcript: Transcript) -> Self {
+        Commander {
+            transcript: Some(Arc::new(transcript)),
+            ..self
+        }
+    }
+
+    pub fn with_host_executor(self, host_executor: HostExecutor) -> Self {
+        Commander {
+            host_executor,
+            ..self
+        }
+    }
+
+    /// Returns a Commander that runs commands on the host.
+    pub(crate) fn on_host(&self) -> Commander {
+        Commander {
+            on_host: true,
+            ..self.clone()
+        }
+    }
+
+    /// Where a path on the host can be found from our container.
+    pub(crate) fn host_path(&self, path: &str) -> String {
+        match &self.host_root {
+            Some(root) => format!("{root}{path}"),
+            None => self.host_executor.host_path(path),
         }
-        output
     }
 
-    fn unchecked_output(&self, args: &[&str]) -> Output {
+    /// The full arguments to run, including any needed to run on the host.
+    fn full_args<'a>(&self, args: &[&'a str]) -> Vec<&'a str> {
+        let prefix = if self.on_host {
+            self.host_executor.prefix()
+        } else {
+            &[]
+        };
+        prefix.iter().chain(args).copied().collect()
+    }
+
+    pub fn is_dry_run(&self) -> bool {
+        self.plan.is_some()
+    }
+
+    /// Returns the changes recorded during a dry run, in order.
+    pub fn plan(&self) -> Vec<PlanStep> {
+        self.plan
+            .as_ref()
+            .map(|plan| plan.lock().unwrap().clone())
+            .unwrap_or_default()
+    }
+
+    /// Renders the changes recorded during a dry run.
+    pub fn format_plan(&self, format: PlanFormat) -> String {
+        let plan = self.plan();
+        match format {
+            PlanFormat::Json => serde_json::to_string_pretty(&plan).unwrap(),
+            PlanFormat::Text if plan.is_empty() => "No changes would be made.".to_owned(),
+            PlanFormat::Text => plan
+                .iter()
+                .enumerate()
+                .map(|(i, step)| format!("{}. {step}", i + 1))
+                .collect::<Vec<_>>()
+                .join("\n"),
+        }
+    }
+
+    /// Records the step if this is a dry run.
+    /// Returns whether the caller should actually make the change.
+    fn record(&self, step: PlanStep) -> bool {
+        match &self.plan {
+            Some(plan) => {
+                info!("Dry run, skipping: {step}");
+                plan.lock().unwrap().push(step);
+                false
+            }
+            None => true,
+        }
+    }
+
+    /// Like [`Commander::record`], for changes that aren't commands or files.
+    pub(crate) fn should_perform(&self, description: String) -> bool {
+        self.record(PlanStep::Action { description })
+    }
+
+    /// Runs a command that changes the system.
+    /// In a dry run, this only records the command and pretends it succeeded.
+    async fn mutating_output(&self, args: &[&str]) -> Result<Output> {
+        self.mutating_output_inner(args, false).await
+    }
+
+    /// Like [`Commander::mutating_output`], for commands that are safe to
+    /// run again, so they are retried according to the policy if they fail.
+    async fn idempotent_output(&self, args: &[&str]) -> Result<Output> {
+        self.mutating_output_inner(args, true).await
+    }
+
+    async fn mutating_output_inner(&self, args: &[&str], retry: bool) -> Result<Output> {
+        let step = PlanStep::Command {
+            args: self
+                .full_args(args)
+                .iter()
+                .map(|arg| (*arg).to_owned())
+                .collect(),
+        };
+        if self.record(step) {
+            self.checked_output_with_retries(args, retry).await
+        } else {
+            Ok(Output {
+                status: ExitStatus::from_raw(0),
+                stdout: Vec::new(),
+                stderr: Vec::new(),
+            })
+        }
+    }
+
+    /// Writes a file, creating its parent directories,
+    /// or only records it in a dry run.
+    fn write_file(&self, path: &str, contents: &str) -> Result<()> {
+        let step = PlanStep::WriteFile {
+            path: path.to_owned(),
+            contents: contents.to_owned(),
+        };
+        if self.record(step) {
+            if let Some(parent) = std::path::Path::new(path).parent() {
+                std::fs::create_dir_all(parent)
+                    .map_err(|e| Error::io(format!("Failed to create {}", parent.display()), e))?;
+            }
+            std::fs::write(path, contents)
+                .map_err(|e| Error::io(format!("Failed to write {path}"), e))?;
+        }
+        Ok(())
+    }
+
+    /// Removes a file if it exists, or only records it in a dry run.
+    fn remove_file(&self, path: &str) -> Result<()> {
+        if !std::fs::exists(path).map_err(|e| Error::io(format!("Failed to check {path}"), e))? {
+            return Ok(());
+        }
+        let step = PlanStep::Action {
+            description: format!("remove {path}"),
+        };
+        if self.record(step) {
+            match std::fs::remove_file(path) {
+                Ok(()) => {}
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => return Err(Error::io(format!("Failed to remove {path}"), e)),
+            }
+        }
+        Ok(())
+    }
+
+    /// Runs a read-only command, retrying it according to the policy if it fails.
+    async fn check_output(&self, args: &[&str]) -> Result<Output> {
+        self.checked_output_with_retries(args, true).await
+    }
+
+    async fn checked_output_with_retries(&self, args: &[&str], retry: bool) -> Result<Output> {
+        let mut backoff = self.policy.retry_backoff;
+        let mut attempt = 0;
+        loop {
+            let result = self.unchecked_output(args).await.and_then(|output| {
+                if output.status.success() {
+                    Ok(output)
+                } else {
+                    Err(Error::CommandFailed {
+                        args: args.iter().map(|arg| (*arg).to_owned()).collect(),
+                        code: output.status.code(),
+                        stdout: String::from_utf8_lossy(&output.stdout).into_owned(),
+                        stderr: String::from_utf8_lossy(&output.stderr).into_owned(),
+                    })
+                }
+            });
+            match result {
+                Err(e @ (Error::CommandFailed { .. } | Error::CommandTimedOut { .. }))
+                    if retry && attempt < self.policy.retries =>
+                {
+                    attempt += 1;
+                    warn!(
+                        "{e}\nRetrying in {backoff:?} (retry {attempt} of {})",
+                        self.policy.retries
+                    );
+                    tokio::time::sleep(backoff).await;
+                    backoff *= 2;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    async fn unchecked_output(&self, args: &[&str]) -> Result<Output> {
+        let args = self.full_args(args);
+        let start = Instant::now();
+        let result = self.run(&args).await;
+        if let Some(transcript) = &self.transcript {
+            transcript.record(&args, &self.envs, &result, start.elapsed());
+        }
+        result
+    }
+
+    async fn run(&self, args: &[&str]) -> Result<Output> {
+        let timeout = match self.policy.deadline {
+            Some(deadline) => {
+                let remaining = deadline.saturating_duration_since(Instant::now());
+                if remaining.is_zero() {
+                    return Err(Error::DeadlineExceeded {
+                        args: args.iter().map(|arg| (*arg).to_owned()).collect(),
+                    });
+                }
+                Some(self.policy.timeout.map_or(remaining, |t| t.min(remaining)))
+            }
+            None => self.policy.timeout,
+        };
+
         // We still check if we can even spawn the process,
         // we just don't check the return code.
-        let failure_msg = format!("Failed to spawn '{args:?}'");
-        Command::new(args[0])
+        let child = Command::new(args[0])
             .args(&args[1..])
             .envs(&self.envs)
-            .output()
-            .expect(&failure_msg)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            // Put the child in its own process group, so we can kill
+            // anything it started too if it times out.
+            .process_group(0)
+            .kill_on_drop(true)
+            .spawn()
+            .map_err(|source| Error::CommandSpawn {
+                args: args.iter().map(|arg| (*arg).to_owned()).collect(),
+                source,
+            })?;
+        let pid = child.id();
+        let output = child.wait_with_output();
+        let output = match timeout {
+            Some(timeout) => match tokio::time::timeout(timeout, output).await {
+                Ok(output) => output,
+                Err(_) => {
+                    // The child itself is killed when the future is dropped,
+                    // but anything it started would be left behind.
+                    if let Some(pid) = pid {
+                        // SAFETY: kill has no memory safety requirements.
+                        unsafe { libc::kill(-(pid as libc::pid_t), libc::SIGKILL) };
+                    }
+                    return Err(Error::CommandTimedOut {
+                        args: args.iter().map(|arg| (*arg).to_owned()).collect(),
+                        timeout,
+                    });
+                }
+            },
+            None => output.await,
+        };
+        output.map_err(|e| Error::io(format!("Failed to wait for '{args:?}'"), e))
     }
 }
 
-pub async fn load_kube_config() -> kube::Config {
-    let mut config = kube::Config::incluster().unwrap();
+pub async fn load_kube_config() -> Result<kube::Config> {
+    let mut config = kube::Config::incluster()?;
 
     config.connect_timeout = Some(Duration::from_secs(30));
     config.read_timeout = Some(Duration::from_secs(30));
     config.write_timeout = Some(Duration::from_secs(30));
 
-    config
+    Ok(config)
 }
 
 #[cfg(test)]
@@ -73,9 +433,12 @@ mod test {
     use std::os::unix::fs::OpenOptionsExt;
     use std::path::PathBuf;
 
+    use std::time::{Duration, Instant};
+
     use tempfile::TempDir;
 
-    use crate::Commander;
+    use crate::error::Error;
+    use crate::{CommandPolicy, Commander, HostExecutor, PlanFormat};
 
     pub(crate) struct TestEnv {
         pub(crate) temp_dir: TempDir,
@@ -99,6 +462,19 @@ mod test {
         }
 
         pub(crate) fn mock(&self, command: &str, exit_code: u8, output: &str) {
+            self.mock_script(
+                command,
+                &format!(
+                    "cat <<'EOF'
+{output}
+EOF
+exit {exit_code}"
+                ),
+            );
+        }
+
+        /// Mocks a command with an arbitrary bash script.
+        pub(crate) fn mock_script(&self, command: &str, script: &str) {
             let mut file = OpenOptions::new()
                 .write(true)
                 .truncate(true)
@@ -110,10 +486,7 @@ mod test {
                 format!(
                     "#!/bin/bash
 set -euo pipefail
-cat <<'EOF'
-{output}
-EOF
-exit {exit_code}
+{script}
 "
                 )
                 .as_bytes(),
@@ -126,4 +499,80 @@ exit {exit_code}
             std::fs::read_to_string(PathBuf::from(env!("CARGO_MANIFEST_DIR")).join(path)).unwrap()
         }
     }
+
+    #[tokio::test]
+    async fn test_on_host() {
+        let commander = Commander::dry_run();
+        commander
+            .on_host()
+            .mutating_output(&["systemctl", "daemon-reload"])
+            .await
+            .unwrap();
+        let commander = commander.with_host_executor(HostExecutor::Nsenter);
+        commander
+            .on_host()
+            .mutating_output(&["systemctl", "daemon-reload"])
+            .await
+            .unwrap();
+        commander
+            .mutating_output(&["swapon", "/dev/nvme1n1"])
+            .await
+            .unwrap();
+        assert_eq!(
+            commander.format_plan(PlanFormat::Text),
+            "1. run: chroot /host systemctl daemon-reload
+2. run: nsenter --target 1 --mount --uts --ipc --net --pid --cgroup -- systemctl daemon-reload
+3. run: swapon /dev/nvme1n1"
+        );
+        assert_eq!(
+            commander.host_path("/etc/sysctl.d"),
+            "/proc/1/root/etc/sysctl.d"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_command_timeout() {
+        let test_env = TestEnv::new();
+        test_env.mock_script("hang", "sleep 30");
+        let commander = test_env.commander.clone().with_policy(CommandPolicy {
+            timeout: Some(Duration::from_millis(200)),
+            ..Default::default()
+        });
+        let start = Instant::now();
+        let result = commander.check_output(&["hang"]).await;
+        assert!(matches!(result, Err(Error::CommandTimedOut { .. })));
+        assert!(start.elapsed() < Duration::from_secs(10));
+
+        let commander = test_env.commander.clone().with_policy(CommandPolicy {
+            deadline: Some(Instant::now()),
+            ..Default::default()
+        });
+        let result = commander.check_output(&["hang"]).await;
+        assert!(matches!(result, Err(Error::DeadlineExceeded { .. })));
+    }
+
+    #[tokio::test]
+    async fn test_command_retries() {
+        let test_env = TestEnv::new();
+        let counter = test_env.temp_dir.path().join("attempts");
+        // Fails the first two times it is run.
+        test_env.mock_script(
+            "flaky",
+            &format!(
+                "echo x >> {counter}\n[ $(wc -l < {counter}) -gt 2 ]",
+                counter = counter.display()
+            ),
+        );
+        let commander = test_env.commander.clone().with_policy(CommandPolicy {
+            retries: 2,
+            ..Default::default()
+        });
+        commander.check_output(&["flaky"]).await.unwrap();
+
+        // Mutating commands aren't retried unless they're idempotent.
+        std::fs::remove_file(&counter).unwrap();
+        let result = commander.mutating_output(&["flaky"]).await;
+        assert!(matches!(result, Err(Error::CommandFailed { .. })));
+        commander.idempotent_output(&["flaky"]).await.unwrap();
+    }
 }
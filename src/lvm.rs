@@ -1,10 +1,20 @@
+use clap::ValueEnum;
+use futures::{StreamExt, TryStreamExt, stream};
 use serde::Deserialize;
-use tracing::info;
+use tracing::{info, warn};
 
 use crate::Commander;
+use crate::crypt::CryptConfig;
 use crate::detect::DiskDetectorTrait;
+use crate::error::{Error, Result};
 use crate::remove_taint::remove_taint;
 
+/// Tag added to volume groups we create, so we can recognize them later.
+pub(crate) const VG_TAG: &str = "ephemeral-storage-setup";
+
+/// Tag added to the persistent physical volume in cache mode, so teardown leaves it alone.
+pub(crate) const ORIGIN_PV_TAG: &str = "ephemeral-storage-setup-origin";
+
 #[derive(Deserialize)]
 struct LvmReportWrapper {
     report: Vec<LvmReport>,
@@ -14,16 +24,83 @@ struct LvmReportWrapper {
 struct LvmReport {
     vg: Option<Vec<VgReport>>,
     pv: Option<Vec<PvReport>>,
+    lv: Option<Vec<LvReport>>,
 }
 
 #[derive(Deserialize)]
 struct VgReport {
     vg_name: String,
+    // Only present if explicitly requested with `-o`.
+    // LVM reports all numbers as strings in json.
+    vg_missing_pv_count: Option<String>,
+    // Comma separated list of tags. Only present if explicitly requested with `-o`.
+    vg_tags: Option<String>,
 }
 
 #[derive(Deserialize)]
 struct PvReport {
     pv_name: String,
+    // Only present if explicitly requested with `-o`.
+    vg_name: Option<String>,
+    // Comma separated list of tags. Only present if explicitly requested with `-o`.
+    pv_tags: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct LvReport {
+    lv_name: String,
+    // Segment type of the LV (linear, striped, cache, writecache, etc...)
+    segtype: String,
+}
+
+/// Which device-mapper target to use when caching the origin LV.
+#[derive(ValueEnum, Clone, Copy, Debug)]
+pub enum CacheType {
+    /// dm-cache, which caches both reads and writes.
+    Cache,
+    /// dm-writecache, which only caches writes.
+    Writecache,
+}
+
+impl CacheType {
+    fn segtype(&self) -> &'static str {
+        match self {
+            CacheType::Cache => "cache",
+            CacheType::Writecache => "writecache",
+        }
+    }
+}
+
+/// Write policy for dm-cache. Ignored for dm-writecache,
+/// which is always writeback.
+#[derive(ValueEnum, Clone, Copy, Debug)]
+pub enum CacheMode {
+    /// Writes go to both the cache and the origin.
+    /// The origin is always consistent, so losing the cache is safe.
+    Writethrough,
+    /// Writes go only to the cache, and are flushed to the origin later.
+    /// Losing the cache loses any writes not yet flushed.
+    Writeback,
+}
+
+impl CacheMode {
+    fn as_str(&self) -> &'static str {
+        match self {
+            CacheMode::Writethrough => "writethrough",
+            CacheMode::Writeback => "writeback",
+        }
+    }
+}
+
+/// Configuration for using the ephemeral disks as a cache in front of
+/// a logical volume on a slower persistent disk.
+pub struct LvmCacheConfig {
+    /// Persistent device to create the origin LV on.
+    pub origin_device: String,
+    /// Name of the origin LV.
+    pub origin_lv_name: String,
+    pub cache_type: CacheType,
+    pub cache_mode: CacheMode,
 }
 
 pub struct LvmController<D: DiskDetectorTrait> {
@@ -33,71 +110,707 @@ pub struct LvmController<D: DiskDetectorTrait> {
     pub taint_key: String,
     pub remove_taint: bool,
     pub vg_name: String,
+    pub cache: Option<LvmCacheConfig>,
+    /// How many devices to prepare at once.
+    pub max_parallel_devices: usize,
+    /// If set, the physical volumes go on dm-crypt mappings over the devices.
+    pub encryption: Option<CryptConfig>,
 }
 
 impl<D: DiskDetectorTrait> LvmController<D> {
-    pub async fn setup(&self) {
+    pub async fn setup(&self) -> Result<()> {
         info!("Starting NVMe disk configuration with LVM...");
-        if self.volume_group_exists() {
-            info!("Volume group {} already exists.", self.vg_name);
-        } else {
-            let devices = self.disk_detector.detect_devices();
-            for device in &devices {
-                if !self.physical_volume_exists(device) {
-                    self.pvcreate(device);
-                }
-            }
-            self.vgcreate(&devices);
+        match &self.cache {
+            Some(cache) => self.setup_cache(cache).await?,
+            None => self.setup_volume_group().await?,
         }
         info!("LVM setup completed successfully");
         if self.remove_taint {
             remove_taint(
+                &self.commander,
                 self.node_name.as_ref().expect("clap enforced"),
                 &self.taint_key,
             )
-            .await;
+            .await?;
+        }
+        Ok(())
+    }
+
+    async fn setup_volume_group(&self) -> Result<()> {
+        let opened = self.open_encrypted_devices().await?;
+        if self.volume_group_exists().await? {
+            info!("Volume group {} already exists.", self.vg_name);
+        } else {
+            let devices = self.devices(opened).await?;
+            self.create_physical_volumes(&devices).await?;
+            self.vgcreate(&devices).await?;
         }
+        Ok(())
     }
 
-    fn volume_group_exists(&self) -> bool {
-        let vgs_report = self
-            .commander
-            .check_output(&["vgs", "--reportformat", "json"]);
-        let vgs_report: LvmReportWrapper = serde_json::from_slice(&vgs_report.stdout)
-            .expect("Failed to deserialize output of 'vgs --reportformat json'");
-        vgs_report.report[0]
+    async fn setup_cache(&self, cache: &LvmCacheConfig) -> Result<()> {
+        let opened = self.open_encrypted_devices().await?;
+        let origin_device = cache.origin_device.as_str();
+        // In a dry run, a volume group we only planned to create can't be queried,
+        // so it's assumed to be empty.
+        let planned = if self.volume_group_exists().await? {
+            info!("Volume group {} already exists.", self.vg_name);
+            false
+        } else {
+            if !self.physical_volume_exists(origin_device).await? {
+                // Unlike the ephemeral disks, we don't force this,
+                // so that we never clobber existing data on a persistent disk.
+                info!("Creating physical volume on {origin_device}");
+                self.commander
+                    .mutating_output(&["pvcreate", origin_device])
+                    .await?;
+            }
+            self.vgcreate(&[origin_device.to_owned()]).await?;
+            self.commander.is_dry_run()
+        };
+        if !self.physical_volume_tagged(origin_device).await? {
+            info!("Tagging {origin_device} as the cache origin");
+            self.commander
+                .mutating_output(&["pvchange", "--addtag", ORIGIN_PV_TAG, origin_device])
+                .await?;
+        }
+
+        if !planned && self.missing_physical_volume_count().await? > 0 {
+            // The ephemeral disks are gone, most likely because the instance
+            // was stopped and started again. Detach what is left of the cache
+            // so the origin is usable again, then forget the missing disks.
+            warn!(
+                "Volume group {} is missing physical volumes, assuming the ephemeral cache was lost.",
+                self.vg_name
+            );
+            if self.is_cached(&cache.origin_lv_name).await? {
+                if let (CacheType::Cache, CacheMode::Writeback) =
+                    (cache.cache_type, cache.cache_mode)
+                {
+                    warn!("Cache was in writeback mode, unflushed writes have been lost.");
+                }
+                self.uncache(&cache.origin_lv_name).await?;
+            }
+            info!("Removing missing physical volumes from {}", self.vg_name);
+            self.commander
+                .mutating_output(&["vgreduce", "--removemissing", "--force", &self.vg_name])
+                .await?;
+        }
+
+        if planned || self.logical_volume(&cache.origin_lv_name).await?.is_none() {
+            info!(
+                "Creating origin logical volume {} on {origin_device}",
+                cache.origin_lv_name
+            );
+            self.commander
+                .mutating_output(&[
+                    "lvcreate",
+                    "--yes",
+                    "--name",
+                    &cache.origin_lv_name,
+                    "--extents",
+                    "100%PVS",
+                    &self.vg_name,
+                    origin_device,
+                ])
+                .await?;
+        }
+
+        if !planned && self.is_cached(&cache.origin_lv_name).await? {
+            info!("Logical volume {} is already cached.", cache.origin_lv_name);
+            return Ok(());
+        }
+
+        let devices = self.devices(opened).await?;
+        self.create_physical_volumes(&devices).await?;
+        self.vgextend(&devices).await?;
+        self.attach_cache(cache, &devices).await
+    }
+
+    /// Creates a logical volume striped across all of the ephemeral disks, unless it already exists,
+    /// returning its device-mapper path. The rest of the volume group is left free for other uses.
+    ///
+    /// `size_args` are passed to lvcreate, like `--size 1024k` or `--extents 50%VG`.
+    pub(crate) async fn setup_striped_volume(
+        &self,
+        lv_name: &str,
+        size_args: &[String],
+    ) -> Result<String> {
+        let opened = self.open_encrypted_devices().await?;
+        let devices = if self.volume_group_exists().await? {
+            info!("Volume group {} already exists.", self.vg_name);
+            None
+        } else {
+            let devices = self.devices(opened).await?;
+            self.create_physical_volumes(&devices).await?;
+            self.vgcreate(&devices).await?;
+            Some(devices)
+        };
+
+        // In a dry run, a volume group we only planned to create can't be queried,
+        // so it's assumed to be empty.
+        let planned = devices.is_some() && self.commander.is_dry_run();
+        if !planned && self.logical_volume(lv_name).await?.is_some() {
+            info!("Logical volume {lv_name} already exists.");
+        } else {
+            let devices = match devices {
+                Some(devices) => devices,
+                None => self.physical_volumes().await?,
+            };
+            info!("Creating logical volume {lv_name} striped across {devices:?}");
+            let stripes = devices.len().to_string();
+            let mut args = vec!["lvcreate", "--yes", "--name", lv_name];
+            args.extend(size_args.iter().map(String::as_str));
+            if devices.len() > 1 {
+                args.extend(["--stripes", &stripes]);
+            }
+            args.push(&self.vg_name);
+            args.extend(devices.iter().map(String::as_str));
+            self.commander.mutating_output(&args).await?;
+        }
+        Ok(dm_path(&self.vg_name, lv_name))
+    }
+
+    /// If encrypting, detects the devices and opens mappings over them, returning the mappings.
+    ///
+    /// LVM can't see what's on encrypted devices until they're opened, so this has to
+    /// happen before looking for our volume group. With a random key, the old contents
+    /// are unreadable after a reboot, so the volume group is simply created again.
+    async fn open_encrypted_devices(&self) -> Result<Option<Vec<String>>> {
+        let Some(crypt) = &self.encryption else {
+            return Ok(None);
+        };
+        let devices = self.disk_detector.detect_devices().await?;
+        let mappings = stream::iter(&devices)
+            .map(|device| crypt.open(&self.commander, device))
+            .buffered(self.max_parallel_devices.max(1))
+            .try_collect()
+            .await?;
+        Ok(Some(mappings))
+    }
+
+    /// The devices to create physical volumes on, detecting them if they weren't already opened.
+    async fn devices(&self, opened: Option<Vec<String>>) -> Result<Vec<String>> {
+        match opened {
+            Some(mappings) => Ok(mappings),
+            None => self.disk_detector.detect_devices().await,
+        }
+    }
+
+    async fn lvm_report(&self, args: &[&str]) -> Result<LvmReport> {
+        let output = self.commander.check_output(args).await?;
+        let report: LvmReportWrapper = serde_json::from_slice(&output.stdout)
+            .map_err(|e| Error::parse(format!("Failed to deserialize output of '{args:?}'"), e))?;
+        report
+            .report
+            .into_iter()
+            .next()
+            .ok_or_else(|| Error::parse(format!("Empty report from '{args:?}'"), "no reports"))
+    }
+
+    pub(crate) async fn volume_group_exists(&self) -> Result<bool> {
+        Ok(self
+            .lvm_report(&["vgs", "--reportformat", "json"])
+            .await?
+            .vg
+            .unwrap_or_default()
+            .iter()
+            .any(|vg| vg.vg_name == self.vg_name))
+    }
+
+    /// Whether our volume group is tagged as ours.
+    pub(crate) async fn volume_group_tagged(&self) -> Result<bool> {
+        Ok(self
+            .lvm_report(&["vgs", "--reportformat", "json", "-o", "vg_name,vg_tags"])
+            .await?
             .vg
-            .as_ref()
-            .unwrap()
+            .unwrap_or_default()
             .iter()
-            .any(|vg| vg.vg_name == self.vg_name)
+            .any(|vg| {
+                vg.vg_name == self.vg_name
+                    && vg
+                        .vg_tags
+                        .as_deref()
+                        .is_some_and(|tags| has_tag(tags, VG_TAG))
+            }))
     }
 
-    fn physical_volume_exists(&self, device: &str) -> bool {
-        let pvs_report = self
-            .commander
-            .check_output(&["pvs", "--reportformat", "json"]);
-        let pvs_report: LvmReportWrapper = serde_json::from_slice(&pvs_report.stdout)
-            .expect("Failed to deserialize output of 'pvs --reportformat json'");
-        pvs_report.report[0]
+    async fn missing_physical_volume_count(&self) -> Result<usize> {
+        let vg = self
+            .lvm_report(&[
+                "vgs",
+                "--reportformat",
+                "json",
+                "-o",
+                "vg_name,vg_missing_pv_count",
+                &self.vg_name,
+            ])
+            .await?
+            .vg
+            .unwrap_or_default()
+            .into_iter()
+            .find(|vg| vg.vg_name == self.vg_name);
+        match vg.and_then(|vg| vg.vg_missing_pv_count) {
+            Some(count) => count
+                .parse()
+                .map_err(|e| Error::parse("Invalid vg_missing_pv_count", e)),
+            None => Ok(0),
+        }
+    }
+
+    async fn physical_volume_exists(&self, device: &str) -> Result<bool> {
+        Ok(self
+            .lvm_report(&["pvs", "--reportformat", "json"])
+            .await?
+            .pv
+            .unwrap_or_default()
+            .iter()
+            .any(|pv| pv.pv_name == device))
+    }
+
+    async fn physical_volume_tagged(&self, device: &str) -> Result<bool> {
+        Ok(self
+            .lvm_report(&["pvs", "--reportformat", "json", "-o", "pv_name,pv_tags"])
+            .await?
             .pv
-            .as_ref()
-            .unwrap()
+            .unwrap_or_default()
             .iter()
-            .any(|pv| pv.pv_name == device)
+            .any(|pv| {
+                pv.pv_name == device
+                    && pv
+                        .pv_tags
+                        .as_deref()
+                        .is_some_and(|tags| has_tag(tags, ORIGIN_PV_TAG))
+            }))
+    }
+
+    /// The physical volumes in our volume group.
+    pub(crate) async fn physical_volumes(&self) -> Result<Vec<String>> {
+        Ok(self
+            .lvm_report(&["pvs", "--reportformat", "json", "-o", "pv_name,vg_name"])
+            .await?
+            .pv
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|pv| pv.vg_name.as_deref() == Some(self.vg_name.as_str()))
+            .map(|pv| pv.pv_name)
+            .collect())
     }
 
-    fn pvcreate(&self, device: &str) {
+    async fn logical_volume(&self, lv_name: &str) -> Result<Option<LvReport>> {
+        Ok(self
+            .lvm_report(&[
+                "lvs",
+                "--reportformat",
+                "json",
+                "-o",
+                "lv_name,segtype",
+                &self.vg_name,
+            ])
+            .await?
+            .lv
+            .unwrap_or_default()
+            .into_iter()
+            .find(|lv| lv.lv_name == lv_name))
+    }
+
+    async fn is_cached(&self, lv_name: &str) -> Result<bool> {
+        Ok(self
+            .logical_volume(lv_name)
+            .await?
+            .map(|lv| lv.segtype == "cache" || lv.segtype == "writecache")
+            .unwrap_or(false))
+    }
+
+    /// Creates physical volumes on the devices that don't have one yet,
+    /// up to `max_parallel_devices` at a time.
+    async fn create_physical_volumes(&self, devices: &[String]) -> Result<()> {
+        stream::iter(devices)
+            .map(|device| async move {
+                if !self.physical_volume_exists(device).await? {
+                    self.pvcreate(device).await?;
+                }
+                Ok(())
+            })
+            .buffer_unordered(self.max_parallel_devices.max(1))
+            .try_collect()
+            .await
+    }
+
+    async fn pvcreate(&self, device: &str) -> Result<()> {
         info!("Creating physical volume on {device}");
-        self.commander.check_output(&["pvcreate", "-f", device]);
+        self.commander
+            .mutating_output(&["pvcreate", "-f", device])
+            .await?;
+        Ok(())
     }
 
-    fn vgcreate(&self, devices: &[String]) {
+    async fn vgcreate(&self, devices: &[String]) -> Result<()> {
         info!("Creating volume group {}", &self.vg_name);
-        let mut args = Vec::with_capacity(devices.len() + 2);
+        let mut args = Vec::with_capacity(devices.len() + 4);
         args.push("vgcreate");
+        args.push("--addtag");
+        args.push(VG_TAG);
         args.push(&self.vg_name);
         args.extend(devices.iter().map(|d| d.as_str()));
-        self.commander.check_output(&args);
+        self.commander.mutating_output(&args).await?;
+        Ok(())
+    }
+
+    async fn vgextend(&self, devices: &[String]) -> Result<()> {
+        info!("Extending volume group {} with {devices:?}", &self.vg_name);
+        let mut args = Vec::with_capacity(devices.len() + 2);
+        args.push("vgextend");
+        args.push(&self.vg_name);
+        args.extend(devices.iter().map(|d| d.as_str()));
+        self.commander.mutating_output(&args).await?;
+        Ok(())
+    }
+
+    async fn attach_cache(&self, cache: &LvmCacheConfig, devices: &[String]) -> Result<()> {
+        let cache_lv_name = format!("{}_cache", cache.origin_lv_name);
+        info!("Creating cache logical volume {cache_lv_name} on {devices:?}");
+        let stripes = devices.len().to_string();
+        let mut args = vec![
+            "lvcreate",
+            "--yes",
+            "--name",
+            &cache_lv_name,
+            "--extents",
+            "100%PVS",
+        ];
+        if devices.len() > 1 {
+            args.extend(["--stripes", &stripes]);
+        }
+        args.push(&self.vg_name);
+        args.extend(devices.iter().map(|d| d.as_str()));
+        self.commander.mutating_output(&args).await?;
+
+        info!(
+            "Attaching {cache_lv_name} to {} as {}",
+            cache.origin_lv_name,
+            cache.cache_type.segtype()
+        );
+        let origin = format!("{}/{}", self.vg_name, cache.origin_lv_name);
+        let mut args = vec![
+            "lvconvert",
+            "--yes",
+            "--type",
+            cache.cache_type.segtype(),
+            "--cachevol",
+            &cache_lv_name,
+        ];
+        if let CacheType::Cache = cache.cache_type {
+            args.extend(["--cachemode", cache.cache_mode.as_str()]);
+        }
+        args.push(&origin);
+        self.commander.mutating_output(&args).await?;
+        Ok(())
+    }
+
+    async fn uncache(&self, lv_name: &str) -> Result<()> {
+        info!("Detaching cache from {lv_name}");
+        let origin = format!("{}/{lv_name}", self.vg_name);
+        self.commander
+            .mutating_output(&["lvconvert", "--yes", "--force", "--uncache", &origin])
+            .await?;
+        Ok(())
+    }
+}
+
+/// Whether a comma separated list of LVM tags has the tag.
+pub(crate) fn has_tag(tags: &str, tag: &str) -> bool {
+    tags.split(',').any(|t| t == tag)
+}
+
+/// Path of a logical volume under /dev/mapper, which is how /proc/swaps reports it.
+/// Device-mapper escapes hyphens in the names by doubling them.
+pub(crate) fn dm_path(vg_name: &str, lv_name: &str) -> String {
+    format!(
+        "/dev/mapper/{}-{}",
+        vg_name.replace('-', "--"),
+        lv_name.replace('-', "--")
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::{Path, PathBuf};
+
+    use super::*;
+    use crate::detect::StaticDisks;
+    use crate::test::TestEnv;
+    use crate::{Commander, PlanStep};
+
+    fn command(args: &[&str]) -> PlanStep {
+        PlanStep::Command {
+            args: args.iter().map(|arg| (*arg).to_owned()).collect(),
+        }
+    }
+
+    fn controller(
+        commander: &Commander,
+        cache: Option<LvmCacheConfig>,
+    ) -> LvmController<StaticDisks> {
+        LvmController {
+            commander: commander.clone(),
+            disk_detector: StaticDisks(vec!["/dev/nvme1n1".to_owned(), "/dev/nvme2n1".to_owned()]),
+            node_name: None,
+            taint_key: "disk-unconfigured".to_owned(),
+            remove_taint: false,
+            vg_name: "instance-store-vg".to_owned(),
+            cache,
+            max_parallel_devices: 1,
+            encryption: None,
+        }
+    }
+
+    fn cache_config(cache_type: CacheType, cache_mode: CacheMode) -> LvmCacheConfig {
+        LvmCacheConfig {
+            origin_device: "/dev/xvdf".to_owned(),
+            origin_lv_name: "cached".to_owned(),
+            cache_type,
+            cache_mode,
+        }
+    }
+
+    /// Mocks the commands that change LVM to record how they were run, returning the record.
+    fn mock_changes(test_env: &TestEnv) -> PathBuf {
+        let record = test_env.temp_dir.path().join("changes");
+        for command in [
+            "pvcreate",
+            "pvchange",
+            "vgcreate",
+            "vgextend",
+            "vgreduce",
+            "lvcreate",
+            "lvconvert",
+        ] {
+            test_env.mock_script(
+                command,
+                &format!(r#"echo "{command} $*" >> {}"#, record.display()),
+            );
+        }
+        record
+    }
+
+    fn changes(record: &Path) -> Vec<String> {
+        std::fs::read_to_string(record)
+            .unwrap_or_default()
+            .lines()
+            .map(str::to_owned)
+            .collect()
+    }
+
+    fn mock_fresh(test_env: &TestEnv) {
+        test_env.mock("vgs", 0, r#"{"report": [{"vg": []}]}"#);
+        test_env.mock("lvs", 0, r#"{"report": [{"lv": []}]}"#);
+        test_env.mock("pvs", 0, r#"{"report": [{"pv": []}]}"#);
+    }
+
+    #[tokio::test]
+    async fn test_setup_cache_fresh() {
+        let test_env = TestEnv::new();
+        mock_fresh(&test_env);
+        let record = mock_changes(&test_env);
+        let cache = cache_config(CacheType::Cache, CacheMode::Writeback);
+        controller(&test_env.commander, Some(cache))
+            .setup()
+            .await
+            .unwrap();
+        assert_eq!(
+            changes(&record),
+            vec![
+                "pvcreate /dev/xvdf",
+                "vgcreate --addtag ephemeral-storage-setup instance-store-vg /dev/xvdf",
+                "pvchange --addtag ephemeral-storage-setup-origin /dev/xvdf",
+                "lvcreate --yes --name cached --extents 100%PVS instance-store-vg /dev/xvdf",
+                "pvcreate -f /dev/nvme1n1",
+                "pvcreate -f /dev/nvme2n1",
+                "vgextend instance-store-vg /dev/nvme1n1 /dev/nvme2n1",
+                "lvcreate --yes --name cached_cache --extents 100%PVS --stripes 2 instance-store-vg /dev/nvme1n1 /dev/nvme2n1",
+                "lvconvert --yes --type cache --cachevol cached_cache --cachemode writeback instance-store-vg/cached",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_setup_writecache_fresh() {
+        let test_env = TestEnv::new();
+        mock_fresh(&test_env);
+        let record = mock_changes(&test_env);
+        // The cache mode only applies to dm-cache.
+        let cache = cache_config(CacheType::Writecache, CacheMode::Writeback);
+        controller(&test_env.commander, Some(cache))
+            .setup()
+            .await
+            .unwrap();
+        assert_eq!(
+            changes(&record).last().unwrap(),
+            "lvconvert --yes --type writecache --cachevol cached_cache instance-store-vg/cached"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_setup_cache_rerun() {
+        let test_env = TestEnv::new();
+        test_env.mock(
+            "vgs",
+            0,
+            r#"{"report": [{"vg": [{"vg_name": "instance-store-vg", "vg_missing_pv_count": "0"}]}]}"#,
+        );
+        test_env.mock(
+            "lvs",
+            0,
+            r#"{"report": [{"lv": [{"lv_name": "cached", "segtype": "cache"}]}]}"#,
+        );
+        test_env.mock(
+            "pvs",
+            0,
+            r#"{"report": [{"pv": [
+                {"pv_name": "/dev/xvdf", "vg_name": "instance-store-vg", "pv_tags": "ephemeral-storage-setup-origin"},
+                {"pv_name": "/dev/nvme1n1", "vg_name": "instance-store-vg", "pv_tags": ""},
+                {"pv_name": "/dev/nvme2n1", "vg_name": "instance-store-vg", "pv_tags": ""}
+            ]}]}"#,
+        );
+        let record = mock_changes(&test_env);
+        let cache = cache_config(CacheType::Cache, CacheMode::Writethrough);
+        controller(&test_env.commander, Some(cache))
+            .setup()
+            .await
+            .unwrap();
+        assert_eq!(changes(&record), Vec::<String>::new());
+    }
+
+    #[tokio::test]
+    async fn test_setup_cache_lost() {
+        let test_env = TestEnv::new();
+        // The instance was stopped and started again, with new ephemeral disks.
+        test_env.mock(
+            "vgs",
+            0,
+            r#"{"report": [{"vg": [{"vg_name": "instance-store-vg", "vg_missing_pv_count": "2"}]}]}"#,
+        );
+        test_env.mock(
+            "pvs",
+            0,
+            r#"{"report": [{"pv": [
+                {"pv_name": "/dev/xvdf", "vg_name": "instance-store-vg", "pv_tags": "ephemeral-storage-setup-origin"}
+            ]}]}"#,
+        );
+        let record = mock_changes(&test_env);
+        // The origin is cached until lvconvert detaches the cache.
+        let uncached = test_env.temp_dir.path().join("uncached");
+        test_env.mock_script(
+            "lvs",
+            &format!(
+                r#"segtype=cache; [ ! -e {uncached} ] || segtype=linear
+echo '{{"report": [{{"lv": [{{"lv_name": "cached", "segtype": "'$segtype'"}}]}}]}}'"#,
+                uncached = uncached.display()
+            ),
+        );
+        test_env.mock_script(
+            "lvconvert",
+            &format!(
+                r#"echo "lvconvert $*" >> {}; touch {}"#,
+                record.display(),
+                uncached.display()
+            ),
+        );
+        let cache = cache_config(CacheType::Cache, CacheMode::Writethrough);
+        controller(&test_env.commander, Some(cache))
+            .setup()
+            .await
+            .unwrap();
+        assert_eq!(
+            changes(&record),
+            vec![
+                "lvconvert --yes --force --uncache instance-store-vg/cached",
+                "vgreduce --removemissing --force instance-store-vg",
+                "pvcreate -f /dev/nvme1n1",
+                "pvcreate -f /dev/nvme2n1",
+                "vgextend instance-store-vg /dev/nvme1n1 /dev/nvme2n1",
+                "lvcreate --yes --name cached_cache --extents 100%PVS --stripes 2 instance-store-vg /dev/nvme1n1 /dev/nvme2n1",
+                "lvconvert --yes --type cache --cachevol cached_cache --cachemode writethrough instance-store-vg/cached",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_setup_cache_dry_run() {
+        let test_env = TestEnv::new();
+        // Like LVM, fail when asked about a volume group that doesn't exist.
+        for (command, report) in [("vgs", "vg"), ("lvs", "lv")] {
+            test_env.mock_script(
+                command,
+                &format!(
+                    r#"for arg in "$@"; do [ "$arg" != instance-store-vg ] || exit 5; done
+echo '{{"report": [{{"{report}": []}}]}}'"#
+                ),
+            );
+        }
+        test_env.mock("pvs", 0, r#"{"report": [{"pv": []}]}"#);
+        let commander = Commander {
+            plan: Some(Default::default()),
+            ..test_env.commander.clone()
+        };
+        let cache = cache_config(CacheType::Cache, CacheMode::Writethrough);
+        controller(&commander, Some(cache)).setup().await.unwrap();
+        assert_eq!(
+            commander.plan(),
+            vec![
+                command(&["pvcreate", "/dev/xvdf"]),
+                command(&[
+                    "vgcreate",
+                    "--addtag",
+                    VG_TAG,
+                    "instance-store-vg",
+                    "/dev/xvdf"
+                ]),
+                command(&["pvchange", "--addtag", ORIGIN_PV_TAG, "/dev/xvdf"]),
+                command(&[
+                    "lvcreate",
+                    "--yes",
+                    "--name",
+                    "cached",
+                    "--extents",
+                    "100%PVS",
+                    "instance-store-vg",
+                    "/dev/xvdf"
+                ]),
+                command(&["pvcreate", "-f", "/dev/nvme1n1"]),
+                command(&["pvcreate", "-f", "/dev/nvme2n1"]),
+                command(&[
+                    "vgextend",
+                    "instance-store-vg",
+                    "/dev/nvme1n1",
+                    "/dev/nvme2n1"
+                ]),
+                command(&[
+                    "lvcreate",
+                    "--yes",
+                    "--name",
+                    "cached_cache",
+                    "--extents",
+                    "100%PVS",
+                    "--stripes",
+                    "2",
+                    "instance-store-vg",
+                    "/dev/nvme1n1",
+                    "/dev/nvme2n1"
+                ]),
+                command(&[
+                    "lvconvert",
+                    "--yes",
+                    "--type",
+                    "cache",
+                    "--cachevol",
+                    "cached_cache",
+                    "--cachemode",
+                    "writethrough",
+                    "instance-store-vg/cached"
+                ]),
+            ]
+        );
     }
 }
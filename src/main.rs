@@ -1,16 +1,29 @@
 use std::io::ErrorKind;
 use std::process::exit;
 use std::thread::sleep;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use clap::{CommandFactory, Parser, Subcommand};
 
-use ephemeral_storage_setup::detect::DiskDetector;
-use ephemeral_storage_setup::lvm::LvmController;
-use ephemeral_storage_setup::swap::SwapController;
-use ephemeral_storage_setup::{CloudProvider, Commander};
-use tracing::info;
+use ephemeral_storage_setup::combined::{CombinedController, DiskAllocation};
+use ephemeral_storage_setup::crypt::CryptConfig;
+use ephemeral_storage_setup::detect::{DiskDetector, DiskDetectorTrait, StaticDisks};
+use ephemeral_storage_setup::erase::EraseController;
+use ephemeral_storage_setup::error::{Error, Result};
+use ephemeral_storage_setup::lvm::{CacheMode, CacheType, LvmCacheConfig, LvmController};
+use ephemeral_storage_setup::partition::{PartitionLayout, parse_partition_layout};
+use ephemeral_storage_setup::swap::{
+    KubeletSwapSettings, SwapBehavior, SwapController, SwapDiscard, SwapSize, parse_swap_size,
+};
+use ephemeral_storage_setup::teardown::TeardownController;
+use ephemeral_storage_setup::transcript::{TRANSCRIPT_HOST_PATH, Transcript};
+use ephemeral_storage_setup::tuning::{
+    Tuning, TuningProfile, ZswapConfig, parse_sysctl, parse_sysfs,
+};
+use ephemeral_storage_setup::zram::ZramConfig;
+use ephemeral_storage_setup::{CloudProvider, CommandPolicy, Commander, HostExecutor, PlanFormat};
 use tracing::level_filters::LevelFilter;
+use tracing::{error, info, warn};
 use tracing_subscriber::EnvFilter;
 
 #[derive(Parser)]
@@ -26,6 +39,34 @@ struct CliArgs {
     /// Do not include the executable name in the array.
     #[command(subcommand)]
     command: Option<Commands>,
+
+    /// Don't change anything, only print a plan of the changes that would be made.
+    ///
+    /// Read-only commands, like detecting disks, are still run.
+    #[arg(long, env, global = true)]
+    dry_run: bool,
+
+    /// Format of the plan printed in a dry run.
+    #[arg(long, env, global = true, value_enum, default_value_t = PlanFormat::Text)]
+    plan_format: PlanFormat,
+
+    /// Append a JSON Lines record of every command we run to this file.
+    ///
+    /// Defaults to /var/log/ephemeral-storage-setup.jsonl on the host,
+    /// found through the host executor, so it outlives the pod.
+    #[arg(long, env, global = true)]
+    transcript_path: Option<String>,
+
+    /// Don't record a transcript of the commands we run.
+    #[arg(long, env, global = true, conflicts_with = "transcript_path")]
+    no_transcript: bool,
+
+    /// How to run commands, like systemctl, on the host.
+    ///
+    /// chroot requires the host's root filesystem to be mounted at /host.
+    /// nsenter requires the pod to use the host's PID namespace.
+    #[arg(long, env, global = true, value_enum, default_value_t = HostExecutor::Chroot)]
+    host_executor: HostExecutor,
 }
 
 #[derive(Subcommand)]
@@ -37,42 +78,112 @@ enum Commands {
         /// Name of the LVM volume group to create.
         #[arg(long, env, default_value = "instance-store-vg")]
         vg_name: String,
+
+        /// Persistent device to use as the origin of an LVM cache.
+        ///
+        /// When set, this device is added to the volume group and a logical
+        /// volume is created on it, and the detected ephemeral disks are attached
+        /// to that logical volume as a cache, rather than being used directly.
+        #[arg(long, env)]
+        cache_origin_device: Option<String>,
+
+        /// Name of the logical volume to create on the cache origin device.
+        #[arg(long, env, default_value = "cached")]
+        cache_origin_lv_name: String,
+
+        /// Which kind of cache to attach to the origin logical volume.
+        #[arg(long, env, value_enum, default_value_t = CacheType::Cache)]
+        cache_type: CacheType,
+
+        /// Write policy of the cache. Only applies to the `cache` cache type.
+        ///
+        /// In writeback mode, writes not yet flushed to the origin are lost
+        /// along with the ephemeral disks.
+        #[arg(long, env, value_enum, default_value_t = CacheMode::Writethrough)]
+        cache_mode: CacheMode,
     },
     Swap {
         #[clap(flatten)]
         common_args: CommonArgs,
 
-        /// Enable swap on bottlerocket nodes using its apiclient.
-        #[clap(long, env, group = "swap-hacks")]
-        bottlerocket_enable_swap: bool,
+        #[clap(flatten)]
+        swap_args: Box<SwapArgs>,
+    },
+    /// Set up both swap and an LVM volume group, splitting the disks between them.
+    ///
+    /// Takes the options of the swap command, and uses --vg-name for the volume group.
+    /// The taint is removed once both are set up.
+    #[command(group(
+        clap::ArgGroup::new("allocation")
+            .required(true)
+            .args(["swap_disks", "swap_capacity_percent", "swap_size", "partition_layout"])
+    ))]
+    Combined {
+        #[clap(flatten)]
+        common_args: CommonArgs,
+
+        /// Give this many whole disks to swap, and the rest to the volume group.
+        #[arg(long, env)]
+        swap_disks: Option<usize>,
 
-        /// Enable swap by hackily modifying the kubelet config and restarting it.
-        #[clap(long, env, group = "swap-hacks")]
-        hack_restart_kubelet_enable_swap: bool,
+        /// Give whole disks to swap until they make up at least this percentage
+        /// of the total capacity, and the rest to the volume group.
+        #[arg(long, env, value_parser = clap::value_parser!(u8).range(1..100))]
+        swap_capacity_percent: Option<u8>,
 
-        /// Apply sysctl settings to make swap more effective and safer.
+        /// Partition every disk with GPT, like `swap=2x-memory,lvm=rest`,
+        /// and give the swap partitions to swap and the lvm partitions to the volume group.
         ///
-        /// This doesn't work on bottlerocket.
+        /// Sizes are given like --swap-size, and the last partition may take the `rest`.
+        /// Partitions are aligned to 1MiB. Disks we already partitioned are left as they are.
+        #[arg(long, env, value_parser = parse_partition_layout)]
+        partition_layout: Option<PartitionLayout>,
+
+        #[clap(flatten)]
+        swap_args: Box<SwapArgs>,
+    },
+    /// Undo what the lvm and swap commands set up.
+    ///
+    /// Disables swap devices we created, and removes the volume group we created
+    /// along with its logical and physical volumes. Resources that weren't created
+    /// by this tool are left alone.
+    Teardown {
+        /// Name of the LVM volume group to remove.
+        #[arg(long, env, default_value = "instance-store-vg")]
+        vg_name: String,
+
+        /// Also wipe all signatures from the devices after tearing them down.
+        #[arg(long, env)]
+        wipe: bool,
+
+        #[clap(flatten)]
+        command_policy: CommandPolicyArgs,
+    },
+    /// Securely erase the detected ephemeral disks.
+    ///
+    /// Intended to run when decommissioning a node, after teardown.
+    /// Devices that are in use will not be detected, and so won't be erased.
+    Erase {
+        #[clap(long, env)]
+        cloud_provider: CloudProvider,
+
+        /// Name of the Kubernetes node we are running on.
+        /// This is only used to identify the node in the report.
+        #[clap(long, env)]
+        node_name: Option<String>,
+
+        /// Also write the JSON erase report to this path.
+        /// The report is always printed to stdout.
         #[clap(long, env)]
-        apply_sysctls: bool,
-
-        /// Controls the weight of application data vs filesystem cache
-        /// when moving data out of memory and into swap.
-        /// 0 effectively disables swap, 100 treats them equally.
-        /// For Materialize uses, they are equivalent, so we set it to 100.
-        #[arg(long, env, default_value_t = 100)]
-        vm_swappiness: usize,
-
-        /// Always reserve a minimum amount of actual free RAM.
-        /// Setting this value to 1GiB makes it much less likely that we hit OOM
-        /// while we still have swap space available we could have used.
-        #[arg(long, env, default_value_t = 1048576)]
-        vm_min_free_kbytes: usize,
-
-        /// Increase the aggressiveness of kswapd.
-        /// Higher values will cause kswapd to swap more and earlier.
-        #[arg(long, env, default_value_t = 100)]
-        vm_watermark_scale_factor: usize,
+        report_path: Option<String>,
+
+        /// Number of blocks to read back from each device
+        /// to verify that it was erased.
+        #[clap(long, env, default_value_t = 1024)]
+        verify_samples: usize,
+
+        #[clap(flatten)]
+        command_policy: CommandPolicyArgs,
     },
     /// Don't do anything, just sleep.
     /// This allows us to not need a separate image just to keep
@@ -100,8 +211,333 @@ struct CommonArgs {
 
     #[clap(long, env, requires_if("true", "node_name"))]
     remove_taint: bool,
+
+    /// How many devices to prepare at once.
+    ///
+    /// Steps shared by all devices, like creating the volume group
+    /// or setting sysctls, always run one at a time.
+    #[clap(long, env, default_value_t = 8)]
+    max_parallel_devices: usize,
+
+    #[clap(flatten)]
+    command_policy: CommandPolicyArgs,
+
+    #[clap(flatten)]
+    encryption: EncryptionArgs,
 }
 
+// Options of the swap command, which the combined command shares.
+#[derive(Parser)]
+struct SwapArgs {
+    /// Priority of the swap devices, from 0 to 32767.
+    ///
+    /// All devices get the same priority, so the kernel spreads pages across them,
+    /// rather than filling one device before the next.
+    #[arg(long, env, default_value_t = 10, value_parser = clap::value_parser!(i32).range(0..=32767))]
+    swap_priority: i32,
+
+    /// When to discard freed swap pages on the devices.
+    #[arg(long, env, value_enum, default_value_t = SwapDiscard::None)]
+    swap_discard: SwapDiscard,
+
+    /// How much swap to create, like `2x-memory`, `200GiB`, or `50%` of the disks.
+    ///
+    /// Swap goes on a logical volume striped across the disks, and the rest of
+    /// the volume group is left free for other uses. Without this, swap takes the whole disks.
+    #[arg(long, env, value_parser = parse_swap_size)]
+    swap_size: Option<SwapSize>,
+
+    /// Name of the LVM volume group for the swap logical volume, with --swap-size.
+    /// The combined command sets up its volume group with this name, whichever way the disks are split.
+    #[arg(long, env, default_value = "instance-store-vg")]
+    vg_name: String,
+
+    /// Enable swap on bottlerocket nodes using its apiclient.
+    #[clap(long, env, group = "swap-hacks")]
+    bottlerocket_enable_swap: bool,
+
+    /// Enable swap by hackily modifying the kubelet config and restarting it.
+    #[clap(long, env, group = "swap-hacks")]
+    hack_restart_kubelet_enable_swap: bool,
+
+    /// How long to wait for the kubelet to become healthy, and the node Ready,
+    /// after changing its config. If it doesn't, the original config is restored.
+    /// This is also how long we wait for it to report swap as enabled.
+    #[arg(long, env, default_value_t = 180)]
+    kubelet_health_timeout_secs: u64,
+
+    #[clap(flatten)]
+    kubelet_swap_args: KubeletSwapArgs,
+
+    #[clap(flatten)]
+    compressed_swap_args: CompressedSwapArgs,
+
+    /// Apply sysctl settings to make swap more effective and safer.
+    ///
+    /// On bottlerocket, these are set through its apiclient.
+    #[clap(long, env)]
+    apply_sysctls: bool,
+
+    /// Controls the weight of application data vs filesystem cache
+    /// when moving data out of memory and into swap.
+    /// 0 effectively disables swap, 100 treats them equally.
+    /// For Materialize uses, they are equivalent, so we set it to 100.
+    #[arg(long, env, default_value_t = 100)]
+    vm_swappiness: usize,
+
+    /// Always reserve a minimum amount of actual free RAM.
+    /// Setting this value to 1GiB makes it much less likely that we hit OOM
+    /// while we still have swap space available we could have used.
+    #[arg(long, env, default_value_t = 1048576)]
+    vm_min_free_kbytes: usize,
+
+    /// Increase the aggressiveness of kswapd.
+    /// Higher values will cause kswapd to swap more and earlier.
+    #[arg(long, env, default_value_t = 100)]
+    vm_watermark_scale_factor: usize,
+
+    /// Apply a built-in set of sysctl and sysfs settings. May be repeated.
+    ///
+    /// Settings from --apply-sysctls, --sysctl, and --sysfs override these.
+    #[arg(long, env, value_enum, value_delimiter = ',')]
+    tuning_profile: Vec<TuningProfile>,
+
+    /// Set a sysctl, like `vm.page-cluster=0`. May be repeated.
+    #[arg(long, env, value_parser = parse_sysctl)]
+    sysctl: Vec<(String, String)>,
+
+    /// Write a value to a file under /sys, like `/sys/kernel/mm/transparent_hugepage/defrag=madvise`.
+    /// May be repeated.
+    #[arg(long, env, value_parser = parse_sysfs)]
+    sysfs: Vec<(String, String)>,
+
+    /// Also write the tuning to the host's /etc/sysctl.d and /etc/tmpfiles.d,
+    /// so it's applied again at boot. Teardown removes these files.
+    #[arg(long, env)]
+    persist_tuning: bool,
+}
+
+impl SwapArgs {
+    fn tuning(&self) -> Tuning {
+        let mut tuning = Tuning::default();
+        for profile in &self.tuning_profile {
+            tuning.extend(Tuning::profile(*profile));
+        }
+        if let Some(zswap) = self.compressed_swap_args.zswap() {
+            tuning.extend(Tuning::zswap(&zswap));
+        }
+        if self.apply_sysctls {
+            tuning.sysctls.extend([
+                ("vm.swappiness".to_owned(), self.vm_swappiness.to_string()),
+                (
+                    "vm.min_free_kbytes".to_owned(),
+                    self.vm_min_free_kbytes.to_string(),
+                ),
+                (
+                    "vm.watermark_scale_factor".to_owned(),
+                    self.vm_watermark_scale_factor.to_string(),
+                ),
+            ]);
+        }
+        tuning.sysctls.extend(self.sysctl.iter().cloned());
+        tuning.sysfs.extend(self.sysfs.iter().cloned());
+        tuning
+    }
+
+    fn into_controller<D: DiskDetectorTrait>(
+        self,
+        commander: &Commander,
+        common_args: CommonArgs,
+        disk_detector: D,
+    ) -> SwapController<D> {
+        SwapController {
+            cloud_provider: common_args.cloud_provider,
+            commander: commander.clone(),
+            disk_detector,
+            node_name: common_args.node_name,
+            taint_key: common_args.taint_key,
+            remove_taint: common_args.remove_taint,
+            bottlerocket_enable_swap: self.bottlerocket_enable_swap,
+            hack_restart_kubelet_enable_swap: self.hack_restart_kubelet_enable_swap,
+            kubelet_health_timeout: Duration::from_secs(self.kubelet_health_timeout_secs),
+            tuning: self.tuning(),
+            kubelet_settings: self.kubelet_swap_args.into(),
+            persist_tuning: self.persist_tuning,
+            max_parallel_devices: common_args.max_parallel_devices,
+            swap_priority: self.swap_priority,
+            swap_discard: self.swap_discard,
+            encryption: common_args.encryption.into(),
+            zram: self.compressed_swap_args.zram(),
+            swap_size: self.swap_size,
+            vg_name: self.vg_name,
+        }
+    }
+}
+
+#[derive(Parser, Clone)]
+struct CommandPolicyArgs {
+    /// Kill commands that run longer than this many seconds. 0 disables the timeout.
+    #[clap(long, env, default_value_t = 300)]
+    command_timeout_secs: u64,
+
+    /// Fail if setup takes longer than this many seconds in total.
+    /// Running commands are killed when it passes. 0 disables the deadline.
+    #[clap(long, env, default_value_t = 1800)]
+    setup_timeout_secs: u64,
+
+    /// How many times to retry failed commands that are safe to run again,
+    /// like detecting disks, setting sysctls, or restarting the kubelet.
+    #[clap(long, env, default_value_t = 2)]
+    command_retries: u32,
+
+    /// How long to wait before the first retry, in milliseconds.
+    /// This doubles after each retry.
+    #[clap(long, env, default_value_t = 1000)]
+    command_retry_backoff_ms: u64,
+}
+
+impl From<CommandPolicyArgs> for CommandPolicy {
+    fn from(args: CommandPolicyArgs) -> Self {
+        let nonzero_secs = |secs| (secs > 0).then(|| Duration::from_secs(secs));
+        CommandPolicy {
+            timeout: nonzero_secs(args.command_timeout_secs),
+            deadline: nonzero_secs(args.setup_timeout_secs).map(|t| Instant::now() + t),
+            retries: args.command_retries,
+            retry_backoff: Duration::from_millis(args.command_retry_backoff_ms),
+        }
+    }
+}
+
+#[derive(Parser, Clone)]
+struct EncryptionArgs {
+    /// Encrypt the devices with dm-crypt, and use the encrypted mappings instead.
+    #[clap(long, env)]
+    encrypt_devices: bool,
+
+    /// Cipher for device encryption, in the format cryptsetup takes.
+    #[clap(long, env, default_value = "aes-xts-plain64")]
+    encryption_cipher: String,
+
+    /// Key size for device encryption, in bits.
+    #[clap(long, env, default_value_t = 512)]
+    encryption_key_size: usize,
+
+    /// Read the encryption key from this file, like a mounted Kubernetes Secret.
+    ///
+    /// By default, each device gets a random key that is never stored,
+    /// so its contents are lost when the node reboots.
+    #[clap(long, env, requires = "encrypt_devices")]
+    encryption_key_file: Option<String>,
+}
+
+impl From<EncryptionArgs> for Option<CryptConfig> {
+    fn from(args: EncryptionArgs) -> Self {
+        args.encrypt_devices.then_some(CryptConfig {
+            cipher: args.encryption_cipher,
+            key_size: args.encryption_key_size,
+            key_file: args.encryption_key_file,
+        })
+    }
+}
+
+// Kubelet settings, applied with `--bottlerocket-enable-swap` or `--hack-restart-kubelet-enable-swap`.
+#[derive(Parser)]
+struct KubeletSwapArgs {
+    /// How the kubelet lets pods use swap.
+    #[clap(long, env, value_enum, default_value_t = SwapBehavior::LimitedSwap)]
+    swap_behavior: SwapBehavior,
+
+    /// Evict pods immediately when available memory drops below this,
+    /// like `500Mi` or `5%`.
+    #[clap(long, env)]
+    eviction_hard_memory: Option<String>,
+
+    /// Evict pods when available memory stays below this for the grace period,
+    /// like `1Gi` or `10%`.
+    #[clap(long, env, requires = "eviction_soft_grace_period")]
+    eviction_soft_memory: Option<String>,
+
+    /// How long available memory must stay below the soft eviction threshold, like `1m30s`.
+    #[clap(long, env, requires = "eviction_soft_memory")]
+    eviction_soft_grace_period: Option<String>,
+
+    /// Memory to reserve for system daemons, like `1Gi`.
+    #[clap(long, env)]
+    system_reserved_memory: Option<String>,
+
+    /// Memory to reserve for Kubernetes daemons, like `1Gi`.
+    #[clap(long, env)]
+    kube_reserved_memory: Option<String>,
+}
+
+impl From<KubeletSwapArgs> for KubeletSwapSettings {
+    fn from(args: KubeletSwapArgs) -> Self {
+        KubeletSwapSettings {
+            swap_behavior: args.swap_behavior,
+            eviction_hard_memory: args.eviction_hard_memory,
+            eviction_soft_memory: args.eviction_soft_memory,
+            eviction_soft_grace_period: args.eviction_soft_grace_period,
+            system_reserved_memory: args.system_reserved_memory,
+            kube_reserved_memory: args.kube_reserved_memory,
+        }
+    }
+}
+
+// Compressed swap in RAM, in front of the disks. Either zram or zswap, not both.
+#[derive(Parser)]
+struct CompressedSwapArgs {
+    /// Add a zram swap device of this uncompressed size, like `8G`,
+    /// used before the disks.
+    #[clap(long, env, conflicts_with = "zswap")]
+    zram_size: Option<String>,
+
+    /// Compression algorithm for the zram device.
+    #[clap(long, env, default_value = "zstd", requires = "zram_size")]
+    zram_algorithm: String,
+
+    /// Swap priority of the zram device. Must be higher than --swap-priority.
+    #[clap(long, env, default_value_t = 100, value_parser = clap::value_parser!(i32).range(0..=32767), requires = "zram_size")]
+    zram_priority: i32,
+
+    /// Enable zswap, which compresses pages in RAM on their way to the disks.
+    #[clap(long, env)]
+    zswap: bool,
+
+    /// Compression algorithm for zswap.
+    #[clap(long, env, default_value = "zstd", requires = "zswap")]
+    zswap_compressor: String,
+
+    /// Allocator for zswap's compressed pages, like `zsmalloc`.
+    /// Newer kernels only have zsmalloc, and don't take this setting.
+    #[clap(long, env, requires = "zswap")]
+    zswap_zpool: Option<String>,
+
+    /// Most of RAM zswap may use, as a percentage.
+    #[clap(long, env, default_value_t = 20, value_parser = clap::value_parser!(u8).range(1..=100), requires = "zswap")]
+    zswap_max_pool_percent: u8,
+}
+
+impl CompressedSwapArgs {
+    fn zram(&self) -> Option<ZramConfig> {
+        self.zram_size.as_ref().map(|size| ZramConfig {
+            size: size.clone(),
+            algorithm: self.zram_algorithm.clone(),
+            priority: self.zram_priority,
+        })
+    }
+
+    fn zswap(&self) -> Option<ZswapConfig> {
+        self.zswap.then(|| ZswapConfig {
+            compressor: self.zswap_compressor.clone(),
+            zpool: self.zswap_zpool.clone(),
+            max_pool_percent: self.zswap_max_pool_percent,
+        })
+    }
+}
+
+/// Kubernetes reports the contents of this file as the termination message of the container.
+const TERMINATION_LOG_PATH: &str = "/dev/termination-log";
+
 fn print_help_and_exit() -> ! {
     CliArgs::command().print_help().unwrap();
     exit(2)
@@ -115,32 +551,64 @@ fn main() {
                 .with_default_directive(LevelFilter::DEBUG.into())
                 .from_env_lossy(),
         )
+        // Keep stdout clean for machine-readable output, like plans and reports.
+        .with_writer(std::io::stderr)
         .init();
-    let command = args.command.unwrap_or_else(|| {
+    if let Err(e) = run(args) {
+        error!("{e}");
+        // Surface the failure as the pod's termination message, if we're in one.
+        if std::fs::exists(TERMINATION_LOG_PATH).unwrap_or(false) {
+            let _ = std::fs::write(TERMINATION_LOG_PATH, e.to_string());
+        }
+        exit(e.exit_code());
+    }
+}
+
+fn run(args: CliArgs) -> Result<()> {
+    let args = if args.command.is_some() {
+        args
+    } else {
         // If they didn't pass a command, try to detect if we're a bottlerocket
         // bootstrap container with the args in user-data.
         let userdata_path = "/.bottlerocket/bootstrap-containers/current/user-data";
         match std::fs::read_to_string(userdata_path) {
             Ok(userdata) => {
                 info!("Found userdata at '{userdata_path}'");
-                let args: Vec<String> =
-                    serde_json::from_str(&userdata).expect("Userdata must be a json array of args");
+                let args: Vec<String> = serde_json::from_str(&userdata).map_err(|e| {
+                    Error::Config(format!("Userdata must be a json array of args: {e}"))
+                })?;
                 CliArgs::parse_from(
                     // Clap expects the first argument to be the name of the executable,
                     // but it doesn't really make sense for that to be set by the user here.
                     std::iter::once("ephemeral-storage-setup")
                         .chain(args.iter().map(|s| s.as_str())),
                 )
-                .command
-                .unwrap_or_else(|| {
-                    print_help_and_exit();
-                })
             }
             Err(e) if e.kind() == ErrorKind::NotFound => print_help_and_exit(),
-            Err(e) => panic!("{e:?}"),
+            Err(e) => return Err(Error::io(format!("Failed to read {userdata_path}"), e)),
+        }
+    };
+    let Some(command) = args.command else {
+        print_help_and_exit();
+    };
+    let commander = if args.dry_run {
+        Commander::dry_run()
+    } else {
+        Commander::default()
+    }
+    .with_host_executor(args.host_executor);
+    let transcript_path = args
+        .transcript_path
+        .unwrap_or_else(|| args.host_executor.host_path(TRANSCRIPT_HOST_PATH));
+    let commander = match (!args.no_transcript).then(|| Transcript::open(&transcript_path)) {
+        Some(Ok(transcript)) => commander.with_transcript(transcript),
+        // The transcript is only for auditing, so it shouldn't stop us.
+        Some(Err(e)) => {
+            warn!("{e}");
+            commander
         }
-    });
-    let commander = Commander::default();
+        None => commander,
+    };
     match command {
         Commands::Lvm {
             common_args:
@@ -149,66 +617,147 @@ fn main() {
                     node_name,
                     taint_key,
                     remove_taint,
+                    max_parallel_devices,
+                    command_policy,
+                    encryption,
                 },
             vg_name,
+            cache_origin_device,
+            cache_origin_lv_name,
+            cache_type,
+            cache_mode,
         } => {
+            let commander = commander.clone().with_policy(command_policy.into());
             let disk_detector = DiskDetector::new(commander.clone(), cloud_provider);
-            tokio::runtime::Builder::new_current_thread()
-                .enable_all()
-                .build()
-                .unwrap()
-                .block_on(
-                    LvmController {
-                        commander,
-                        disk_detector,
-                        node_name,
-                        taint_key,
-                        remove_taint,
-                        vg_name,
-                    }
-                    .setup(),
-                )
-        }
-        Commands::Swap {
-            common_args:
-                CommonArgs {
-                    cloud_provider,
+            runtime()?.block_on(
+                LvmController {
+                    commander: commander.clone(),
+                    disk_detector,
                     node_name,
                     taint_key,
                     remove_taint,
-                },
-            bottlerocket_enable_swap,
-            hack_restart_kubelet_enable_swap,
-            apply_sysctls,
-            vm_swappiness,
-            vm_min_free_kbytes,
-            vm_watermark_scale_factor,
+                    vg_name,
+                    cache: cache_origin_device.map(|origin_device| LvmCacheConfig {
+                        origin_device,
+                        origin_lv_name: cache_origin_lv_name,
+                        cache_type,
+                        cache_mode,
+                    }),
+                    max_parallel_devices,
+                    encryption: encryption.into(),
+                }
+                .setup(),
+            )?
+        }
+        Commands::Swap {
+            common_args,
+            swap_args,
         } => {
-            let disk_detector = DiskDetector::new(commander.clone(), cloud_provider);
-            tokio::runtime::Builder::new_current_thread()
-                .enable_all()
-                .build()
-                .unwrap()
-                .block_on(
-                    SwapController {
-                        cloud_provider,
-                        commander,
-                        disk_detector,
-                        node_name,
-                        taint_key,
-                        remove_taint,
-                        bottlerocket_enable_swap,
-                        hack_restart_kubelet_enable_swap,
-                        apply_sysctls,
-                        vm_swappiness,
-                        vm_min_free_kbytes,
-                        vm_watermark_scale_factor,
-                    }
+            let commander = commander
+                .clone()
+                .with_policy(common_args.command_policy.clone().into());
+            let disk_detector = DiskDetector::new(commander.clone(), common_args.cloud_provider);
+            runtime()?.block_on(
+                swap_args
+                    .into_controller(&commander, common_args, disk_detector)
                     .setup(),
-                )
+            )?
+        }
+        Commands::Combined {
+            common_args,
+            swap_disks,
+            swap_capacity_percent,
+            partition_layout,
+            swap_args,
+        } => {
+            let commander = commander
+                .clone()
+                .with_policy(common_args.command_policy.clone().into());
+            let disk_detector = DiskDetector::new(commander.clone(), common_args.cloud_provider);
+            let allocation = match (swap_disks, swap_capacity_percent, partition_layout) {
+                (Some(count), _, _) => DiskAllocation::Count(count),
+                (_, Some(percent), _) => DiskAllocation::CapacityPercent(percent),
+                (_, _, Some(layout)) => DiskAllocation::Partitions(layout),
+                (None, None, None) => DiskAllocation::Volume,
+            };
+            let lvm = LvmController {
+                commander: commander.clone(),
+                disk_detector: StaticDisks::default(),
+                node_name: common_args.node_name.clone(),
+                taint_key: common_args.taint_key.clone(),
+                remove_taint: false,
+                vg_name: swap_args.vg_name.clone(),
+                cache: None,
+                max_parallel_devices: common_args.max_parallel_devices,
+                encryption: common_args.encryption.clone().into(),
+            };
+            runtime()?.block_on(
+                CombinedController {
+                    commander: commander.clone(),
+                    disk_detector,
+                    node_name: common_args.node_name.clone(),
+                    taint_key: common_args.taint_key.clone(),
+                    remove_taint: common_args.remove_taint,
+                    allocation,
+                    lvm,
+                    swap: swap_args.into_controller(
+                        &commander,
+                        common_args,
+                        StaticDisks::default(),
+                    ),
+                }
+                .setup(),
+            )?
+        }
+        Commands::Teardown {
+            vg_name,
+            wipe,
+            command_policy,
+        } => {
+            let commander = commander.clone().with_policy(command_policy.into());
+            runtime()?.block_on(
+                TeardownController {
+                    commander: commander.clone(),
+                    vg_name,
+                    wipe,
+                }
+                .teardown(),
+            )?
+        }
+        Commands::Erase {
+            cloud_provider,
+            node_name,
+            report_path,
+            verify_samples,
+            command_policy,
+        } => {
+            let commander = commander.clone().with_policy(command_policy.into());
+            let disk_detector =
+                DiskDetector::new(commander.clone(), cloud_provider).excluding_ours();
+            runtime()?.block_on(
+                EraseController {
+                    commander: commander.clone(),
+                    disk_detector,
+                    node_name,
+                    report_path,
+                    verify_samples,
+                }
+                .erase(),
+            )?
         }
         Commands::Sleep => loop {
             sleep(Duration::from_secs(3600));
         },
     }
+    if commander.is_dry_run() {
+        println!("{}", commander.format_plan(args.plan_format));
+    }
+    Ok(())
+}
+
+fn runtime() -> Result<tokio::runtime::Runtime> {
+    tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .map_err(|e| Error::io("Failed to build tokio runtime", e))
 }
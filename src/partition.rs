@@ -0,0 +1,358 @@
+use std::path::Path;
+use std::time::Duration;
+
+use tracing::info;
+
+use crate::Commander;
+use crate::detect::{device_size, lsblk};
+use crate::error::{Error, Result};
+use crate::swap::{SwapSize, mem_total_kib, parse_swap_size};
+
+const DEVICE_NODE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// What a partition we create is for.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PartitionKind {
+    Swap,
+    Lvm,
+}
+
+impl PartitionKind {
+    const ALL: [PartitionKind; 2] = [PartitionKind::Swap, PartitionKind::Lvm];
+
+    /// The standard GPT partition type GUID for this use.
+    fn type_guid(&self) -> &'static str {
+        match self {
+            PartitionKind::Swap => "0657FD6D-A4AB-43C4-84E5-0933C84B4F4F",
+            PartitionKind::Lvm => "E6D6D379-F507-44C2-A23C-238F2A3DF928",
+        }
+    }
+
+    /// Name given to the partition, so we can recognize it later.
+    fn label(&self) -> &'static str {
+        match self {
+            PartitionKind::Swap => "ephemeral-swap",
+            PartitionKind::Lvm => "ephemeral-lvm",
+        }
+    }
+
+    fn from_type_and_label(part_type: &str, label: &str) -> Option<PartitionKind> {
+        PartitionKind::ALL
+            .into_iter()
+            .find(|kind| kind.type_guid().eq_ignore_ascii_case(part_type) && kind.label() == label)
+    }
+}
+
+/// One partition in a [`PartitionLayout`].
+#[derive(Clone, Debug, PartialEq)]
+pub struct PartitionSpec {
+    pub kind: PartitionKind,
+    /// Size of the partition, with percentages of the disk.
+    /// If `None`, the partition takes the rest of the disk.
+    pub size: Option<SwapSize>,
+}
+
+/// GPT partitions to lay out on each disk, in order.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PartitionLayout(pub Vec<PartitionSpec>);
+
+/// Parses a `--partition-layout` argument, like `swap=2x-memory,lvm=rest`.
+///
+/// Sizes are given like `--swap-size`, and only the last partition may be `rest`.
+pub fn parse_partition_layout(arg: &str) -> std::result::Result<PartitionLayout, String> {
+    let mut specs: Vec<PartitionSpec> = Vec::new();
+    for part in arg.split(',') {
+        let Some((kind, size)) = part.split_once('=') else {
+            return Err(format!("expected kind=size, got '{part}'"));
+        };
+        let kind = match kind.trim() {
+            "swap" => PartitionKind::Swap,
+            "lvm" => PartitionKind::Lvm,
+            kind => return Err(format!("unknown partition kind '{kind}'")),
+        };
+        if specs.iter().any(|spec| spec.kind == kind) {
+            return Err(format!("more than one {} partition", kind.label()));
+        }
+        if specs.last().is_some_and(|spec| spec.size.is_none()) {
+            return Err("only the last partition may take the rest of the disk".to_owned());
+        }
+        let size = match size.trim() {
+            "rest" => None,
+            size => Some(parse_swap_size(size)?),
+        };
+        specs.push(PartitionSpec { kind, size });
+    }
+    Ok(PartitionLayout(specs))
+}
+
+/// Whether lsblk's `parttype` and `partlabel` are one of our partitions.
+pub(crate) fn is_our_partition(part_type: Option<&str>, label: Option<&str>) -> bool {
+    match (part_type, label) {
+        (Some(part_type), Some(label)) => {
+            PartitionKind::from_type_and_label(part_type, label).is_some()
+        }
+        _ => false,
+    }
+}
+
+impl PartitionLayout {
+    pub fn contains(&self, kind: PartitionKind) -> bool {
+        self.0.iter().any(|spec| spec.kind == kind)
+    }
+
+    /// Partitions the device, unless we already have, returning the path of each partition.
+    ///
+    /// A device with partitions that aren't ours, or that don't match the layout, is refused.
+    pub(crate) async fn apply(
+        &self,
+        commander: &Commander,
+        device: &str,
+    ) -> Result<Vec<(PartitionKind, String)>> {
+        let existing = existing_partitions(commander, device).await?;
+        if !existing.is_empty() {
+            let kinds: Vec<PartitionKind> = existing.iter().map(|(kind, _)| *kind).collect();
+            let wanted: Vec<PartitionKind> = self.0.iter().map(|spec| spec.kind).collect();
+            if kinds != wanted {
+                return Err(Error::Refused(format!(
+                    "{device} is already partitioned as {kinds:?}, not {wanted:?}"
+                )));
+            }
+            info!("{device} is already partitioned.");
+            return Ok(existing);
+        }
+
+        let needs = |matches: fn(&SwapSize) -> bool| {
+            self.0
+                .iter()
+                .any(|spec| spec.size.as_ref().is_some_and(matches))
+        };
+        let mem_total = match needs(|size| matches!(size, SwapSize::MemoryMultiple(_))) {
+            true => mem_total_kib()?,
+            false => 0,
+        };
+        let disk_size = match needs(|size| matches!(size, SwapSize::Percent(_))) {
+            true => device_kib(commander, device).await?,
+            false => 0,
+        };
+        info!("Partitioning {device} as {:?}", self.0);
+        let args = self.sgdisk_args(device, mem_total, disk_size);
+        let args: Vec<&str> = args.iter().map(String::as_str).collect();
+        commander.mutating_output(&args).await?;
+        let partitions: Vec<(PartitionKind, String)> = self
+            .0
+            .iter()
+            .enumerate()
+            .map(|(i, spec)| (spec.kind, partition_path(device, i + 1)))
+            .collect();
+        if !commander.is_dry_run() {
+            wait_for_device_nodes(device, &partitions).await?;
+        }
+        Ok(partitions)
+    }
+
+    /// Arguments to sgdisk to replace the partition table with the layout.
+    /// Partitions are aligned to 1MiB.
+    fn sgdisk_args(&self, device: &str, mem_total_kib: u64, disk_kib: u64) -> Vec<String> {
+        let mut args = vec![
+            "sgdisk".to_owned(),
+            "--clear".to_owned(),
+            "--set-alignment=2048".to_owned(),
+        ];
+        for (i, spec) in self.0.iter().enumerate() {
+            let number = i + 1;
+            let end = match &spec.size {
+                Some(size) => format!(
+                    "+{}M",
+                    size_kib(size, mem_total_kib, disk_kib).div_ceil(1024)
+                ),
+                None => "0".to_owned(),
+            };
+            args.extend([
+                format!("--new={number}:0:{end}"),
+                format!("--typecode={number}:{}", spec.kind.type_guid()),
+                format!("--change-name={number}:{}", spec.kind.label()),
+            ]);
+        }
+        args.push(device.to_owned());
+        args
+    }
+}
+
+fn size_kib(size: &SwapSize, mem_total_kib: u64, disk_kib: u64) -> u64 {
+    match size {
+        SwapSize::MemoryMultiple(multiple) => (mem_total_kib as f64 * multiple).ceil() as u64,
+        SwapSize::Bytes(bytes) => bytes.div_ceil(1024),
+        SwapSize::Percent(percent) => disk_kib * u64::from(*percent) / 100,
+    }
+}
+
+/// Our partitions on the device, in order. Fails if it has anyone else's.
+async fn existing_partitions(
+    commander: &Commander,
+    device: &str,
+) -> Result<Vec<(PartitionKind, String)>> {
+    let children = lsblk(
+        commander,
+        &["--output", "PATH,TYPE,PARTTYPE,PARTLABEL", device],
+    )
+    .await?
+    .into_iter()
+    .next()
+    .and_then(|device| device.children)
+    .unwrap_or_default();
+    children
+        .into_iter()
+        .map(|child| {
+            match PartitionKind::from_type_and_label(
+                child.parttype.as_deref().unwrap_or_default(),
+                child.partlabel.as_deref().unwrap_or_default(),
+            ) {
+                Some(kind) => Ok((kind, child.path)),
+                None => Err(Error::Refused(format!(
+                    "{device} has a partition that isn't ours: {}",
+                    child.path
+                ))),
+            }
+        })
+        .collect()
+}
+
+async fn device_kib(commander: &Commander, device: &str) -> Result<u64> {
+    Ok(device_size(commander, device).await? / 1024)
+}
+
+/// Waits for the kernel to create the partitions' device nodes in devtmpfs.
+///
+/// We don't rely on udev, which the image doesn't have,
+/// and which the host may not run at all.
+async fn wait_for_device_nodes(device: &str, partitions: &[(PartitionKind, String)]) -> Result<()> {
+    let start = tokio::time::Instant::now();
+    while !partitions
+        .iter()
+        .all(|(_, partition)| Path::new(partition).exists())
+    {
+        if start.elapsed() >= DEVICE_NODE_TIMEOUT {
+            return Err(Error::Io {
+                context: format!("Partitions of {device} didn't appear"),
+                source: std::io::ErrorKind::TimedOut.into(),
+            });
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    Ok(())
+}
+
+/// Path the kernel gives a partition. Disks whose names end in a digit,
+/// like nvme0n1, get a `p` before the partition number.
+fn partition_path(device: &str, number: usize) -> String {
+    match device.ends_with(|c: char| c.is_ascii_digit()) {
+        true => format!("{device}p{number}"),
+        false => format!("{device}{number}"),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::TestEnv;
+
+    #[test]
+    fn test_parse_partition_layout() {
+        assert_eq!(
+            parse_partition_layout("swap=100GiB,lvm=rest"),
+            Ok(PartitionLayout(vec![
+                PartitionSpec {
+                    kind: PartitionKind::Swap,
+                    size: Some(SwapSize::Bytes(100 << 30)),
+                },
+                PartitionSpec {
+                    kind: PartitionKind::Lvm,
+                    size: None,
+                },
+            ]))
+        );
+        for invalid in ["swap=rest,lvm=rest", "swap=1G,swap=1G", "data=1G", "swap"] {
+            assert!(parse_partition_layout(invalid).is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn test_sgdisk_args() {
+        let layout = parse_partition_layout("swap=2x-memory,lvm=25%").unwrap();
+        assert_eq!(
+            layout.sgdisk_args("/dev/nvme1n1", 16000000, 400000000),
+            vec![
+                "sgdisk",
+                "--clear",
+                "--set-alignment=2048",
+                "--new=1:0:+31250M",
+                "--typecode=1:0657FD6D-A4AB-43C4-84E5-0933C84B4F4F",
+                "--change-name=1:ephemeral-swap",
+                "--new=2:0:+97657M",
+                "--typecode=2:E6D6D379-F507-44C2-A23C-238F2A3DF928",
+                "--change-name=2:ephemeral-lvm",
+                "/dev/nvme1n1",
+            ]
+        );
+        assert_eq!(partition_path("/dev/nvme1n1", 2), "/dev/nvme1n1p2");
+        assert_eq!(partition_path("/dev/sdb", 1), "/dev/sdb1");
+    }
+
+    #[tokio::test]
+    async fn test_apply_existing() {
+        let test_env = TestEnv::new();
+        test_env.mock(
+            "lsblk",
+            0,
+            r#"{"blockdevices": [{"path": "/dev/nvme1n1", "type": "disk", "parttype": null, "partlabel": null, "children": [
+                {"path": "/dev/nvme1n1p1", "type": "part", "parttype": "0657fd6d-a4ab-43c4-84e5-0933c84b4f4f", "partlabel": "ephemeral-swap"},
+                {"path": "/dev/nvme1n1p2", "type": "part", "parttype": "e6d6d379-f507-44c2-a23c-238f2a3df928", "partlabel": "ephemeral-lvm"}
+            ]}]}"#,
+        );
+        // Nothing may be repartitioned.
+        test_env.mock("sgdisk", 1, "");
+        let layout = parse_partition_layout("swap=1G,lvm=rest").unwrap();
+        assert_eq!(
+            layout
+                .apply(&test_env.commander, "/dev/nvme1n1")
+                .await
+                .unwrap(),
+            vec![
+                (PartitionKind::Swap, "/dev/nvme1n1p1".to_owned()),
+                (PartitionKind::Lvm, "/dev/nvme1n1p2".to_owned()),
+            ]
+        );
+
+        let layout = parse_partition_layout("lvm=rest").unwrap();
+        assert!(matches!(
+            layout.apply(&test_env.commander, "/dev/nvme1n1").await,
+            Err(Error::Refused(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_apply_waits_for_device_nodes() {
+        let test_env = TestEnv::new();
+        let device = test_env.temp_dir.path().join("nvme1n1");
+        let device = device.to_str().unwrap();
+        test_env.mock(
+            "lsblk",
+            0,
+            &format!(r#"{{"blockdevices": [{{"path": "{device}", "type": "disk"}}]}}"#),
+        );
+        // The kernel creates the nodes after sgdisk re-reads the partition table.
+        test_env.mock_script(
+            "sgdisk",
+            &format!("(sleep 0.3; touch {device}p1 {device}p2) &"),
+        );
+        let layout = parse_partition_layout("swap=1G,lvm=rest").unwrap();
+        assert_eq!(
+            layout.apply(&test_env.commander, device).await.unwrap(),
+            vec![
+                (PartitionKind::Swap, format!("{device}p1")),
+                (PartitionKind::Lvm, format!("{device}p2")),
+            ]
+        );
+        assert!(Path::new(&format!("{device}p2")).exists());
+    }
+}
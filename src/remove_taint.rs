@@ -3,21 +3,32 @@ use kube::api::PostParams;
 use kube::{Api, Client};
 use tracing::{info, warn};
 
-use crate::load_kube_config;
+use crate::error::{Error, Result};
+use crate::{Commander, load_kube_config};
 
-pub(crate) async fn remove_taint(node_name: &str, taint_key: &str) {
-    let kube_config = load_kube_config().await;
-    let client = Client::try_from(kube_config.clone()).unwrap();
+pub(crate) async fn remove_taint(
+    commander: &Commander,
+    node_name: &str,
+    taint_key: &str,
+) -> Result<()> {
+    if !commander.should_perform(format!("remove taint {taint_key} from node {node_name}")) {
+        return Ok(());
+    }
+    let kube_config = load_kube_config().await?;
+    let client = Client::try_from(kube_config.clone())
+        .map_err(|e| Error::kube("Failed to create kubernetes client", e))?;
     let node_api: Api<Node> = Api::all(client);
 
-    for _ in 0..5 {
+    let mut attempts = 0;
+    loop {
+        attempts += 1;
         let mut node = node_api
             .get(node_name)
             .await
-            .unwrap_or_else(|e| panic!("Failed to get node {node_name}: {e:?}"));
+            .map_err(|e| Error::kube(format!("Failed to get node {node_name}"), e))?;
         let Some(taint_position) = taint_position(taint_key, &node) else {
             info!("Node {node_name} is not tainted");
-            return;
+            return Ok(());
         };
         info!("Removing taint {taint_key} from node {node_name}");
         node.spec
@@ -33,12 +44,17 @@ pub(crate) async fn remove_taint(node_name: &str, taint_key: &str) {
         {
             Ok(_) => {
                 info!("Taint {taint_key} removed");
-                return;
+                return Ok(());
             }
-            Err(kube::Error::Api(e)) if e.code == 409 => {
+            Err(kube::Error::Api(e)) if e.code == 409 && attempts < 5 => {
                 warn!("Conflict while replacing node");
             }
-            Err(e) => panic!("{}", e),
+            Err(e) => {
+                return Err(Error::kube(
+                    format!("Failed to replace node {node_name}"),
+                    e,
+                ));
+            }
         };
     }
 }
@@ -1,14 +1,183 @@
-use std::collections::BTreeMap;
-use std::fs;
-use std::io::ErrorKind;
+use std::time::Duration;
 
-use serde_yaml::{Mapping, Value};
-use tracing::info;
+use clap::ValueEnum;
+use futures::{StreamExt, TryStreamExt, stream};
+use tracing::{error, info, warn};
 
-use crate::detect::DiskDetectorTrait;
+use crate::crypt::{CryptConfig, mapping_path};
+use crate::detect::{DiskDetectorTrait, LsblkBlockDevice};
+use crate::error::{Error, Result};
+use crate::kubelet::{
+    HEALTHZ_ADDR, KubeletConfig, running_config, swap_settings, wait_until_healthy,
+};
+use crate::lvm::LvmController;
 use crate::remove_taint::remove_taint;
+use crate::systemd::Systemd;
+use crate::tuning::{Tuner, Tuning};
+use crate::zram::ZramConfig;
 use crate::{CloudProvider, Commander};
 
+/// Label given to swap devices we create, so we can recognize them later.
+/// Swap labels are limited to 16 characters.
+pub(crate) const SWAP_LABEL: &str = "ephemeral-swap";
+
+/// Whether the device is swap we created.
+pub(crate) fn is_our_swap(device: &LsblkBlockDevice) -> bool {
+    device.fstype.as_deref() == Some("swap") && device.label.as_deref() == Some(SWAP_LABEL)
+}
+
+/// When swap tells the device which pages it no longer uses.
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SwapDiscard {
+    /// Discard the whole device once, when swap is enabled.
+    Once,
+    /// Discard pages as they are freed.
+    Pages,
+    /// Don't discard.
+    #[default]
+    None,
+}
+
+/// Name of the logical volume swap goes on, when sized with `--swap-size`.
+const SWAP_LV_NAME: &str = "swap";
+
+/// How much swap to create, instead of using the whole disks.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SwapSize {
+    /// A multiple of MemTotal, like `2x-memory`.
+    MemoryMultiple(f64),
+    /// A fixed size in bytes, like `200GiB`.
+    Bytes(u64),
+    /// A percentage of the disks, like `50%`.
+    Percent(u8),
+}
+
+impl SwapSize {
+    /// The size as lvcreate arguments, given MemTotal in KiB.
+    fn lvcreate_args(&self, mem_total_kib: u64) -> Vec<String> {
+        match self {
+            SwapSize::MemoryMultiple(multiple) => vec![
+                "--size".to_owned(),
+                format!("{}k", (mem_total_kib as f64 * multiple).ceil() as u64),
+            ],
+            SwapSize::Bytes(bytes) => {
+                vec!["--size".to_owned(), format!("{}k", bytes.div_ceil(1024))]
+            }
+            SwapSize::Percent(percent) => vec!["--extents".to_owned(), format!("{percent}%VG")],
+        }
+    }
+}
+
+/// Parses a `--swap-size` argument, like `2x-memory`, `200GiB`, or `50%`.
+///
+/// Units are binary, so `200G` and `200GiB` are the same.
+pub fn parse_swap_size(arg: &str) -> std::result::Result<SwapSize, String> {
+    let invalid = || format!("expected a size like 2x-memory, 200GiB, or 50%, got '{arg}'");
+    if let Some(multiple) = arg.strip_suffix("x-memory") {
+        let multiple: f64 = multiple.parse().map_err(|_| invalid())?;
+        if !multiple.is_finite() || multiple <= 0.0 {
+            return Err(invalid());
+        }
+        return Ok(SwapSize::MemoryMultiple(multiple));
+    }
+    if let Some(percent) = arg.strip_suffix('%') {
+        return match percent.parse() {
+            Ok(percent @ 1..=100) => Ok(SwapSize::Percent(percent)),
+            _ => Err(invalid()),
+        };
+    }
+    let number_end = arg.find(|c: char| !c.is_ascii_digit()).unwrap_or(arg.len());
+    let (number, unit) = arg.split_at(number_end);
+    let number: u64 = number.parse().map_err(|_| invalid())?;
+    let shift = match unit.trim_end_matches("iB").trim_end_matches('i') {
+        "" | "B" => 0,
+        "K" | "k" => 10,
+        "M" => 20,
+        "G" => 30,
+        "T" => 40,
+        _ => return Err(invalid()),
+    };
+    match number.checked_mul(1 << shift) {
+        Some(bytes) if bytes > 0 => Ok(SwapSize::Bytes(bytes)),
+        _ => Err(invalid()),
+    }
+}
+
+/// How the kubelet lets pods use swap.
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SwapBehavior {
+    /// Burstable pods may use swap, in proportion to their memory requests.
+    #[default]
+    #[value(name = "LimitedSwap")]
+    LimitedSwap,
+    /// Pods don't use swap, though the rest of the system still can.
+    #[value(name = "NoSwap")]
+    NoSwap,
+}
+
+impl SwapBehavior {
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            SwapBehavior::LimitedSwap => "LimitedSwap",
+            SwapBehavior::NoSwap => "NoSwap",
+        }
+    }
+}
+
+/// Kubelet settings to apply when enabling swap.
+///
+/// Memory quantities are in Kubernetes notation, like `500Mi`,
+/// and eviction thresholds may also be percentages, like `5%`.
+/// Settings left as `None` aren't changed.
+#[derive(Clone, Debug, Default)]
+pub struct KubeletSwapSettings {
+    pub swap_behavior: SwapBehavior,
+    /// `memory.available` threshold for hard eviction.
+    pub eviction_hard_memory: Option<String>,
+    /// `memory.available` threshold for soft eviction.
+    pub eviction_soft_memory: Option<String>,
+    /// How long `memory.available` must stay below the soft threshold before evicting,
+    /// like `1m30s`. The kubelet requires this with a soft threshold.
+    pub eviction_soft_grace_period: Option<String>,
+    /// Memory reserved for system daemons.
+    pub system_reserved_memory: Option<String>,
+    /// Memory reserved for Kubernetes daemons, like the kubelet itself.
+    pub kube_reserved_memory: Option<String>,
+}
+
+impl KubeletSwapSettings {
+    /// The settings as `apiclient set` arguments, for Bottlerocket.
+    fn apiclient_settings(&self) -> Vec<String> {
+        let mut settings = vec![format!(
+            "settings.kubernetes.memory-swap-behavior={}",
+            self.swap_behavior.as_str()
+        )];
+        // Keys with dots in them have to be quoted.
+        let optional = [
+            (
+                r#"eviction-hard."memory.available""#,
+                &self.eviction_hard_memory,
+            ),
+            (
+                r#"eviction-soft."memory.available""#,
+                &self.eviction_soft_memory,
+            ),
+            (
+                r#"eviction-soft-grace-period."memory.available""#,
+                &self.eviction_soft_grace_period,
+            ),
+            ("system-reserved.memory", &self.system_reserved_memory),
+            ("kube-reserved.memory", &self.kube_reserved_memory),
+        ];
+        for (key, value) in optional {
+            if let Some(value) = value {
+                settings.push(format!("settings.kubernetes.{key}={value}"));
+            }
+        }
+        settings
+    }
+}
+
 pub struct SwapController<D: DiskDetectorTrait> {
     pub cloud_provider: CloudProvider,
     pub commander: Commander,
@@ -17,141 +186,544 @@ pub struct SwapController<D: DiskDetectorTrait> {
     pub taint_key: String,
     pub bottlerocket_enable_swap: bool,
     pub hack_restart_kubelet_enable_swap: bool,
+    /// What to configure the kubelet with, when enabling swap in it.
+    pub kubelet_settings: KubeletSwapSettings,
+    /// How long to wait for the kubelet to come back after restarting it,
+    /// before restoring its original config, and for it to report swap as enabled.
+    pub kubelet_health_timeout: Duration,
     pub remove_taint: bool,
-    pub apply_sysctls: bool,
-    pub vm_swappiness: usize,
-    pub vm_min_free_kbytes: usize,
-    pub vm_watermark_scale_factor: usize,
+    /// Sysctls and sysfs settings to apply after enabling swap.
+    pub tuning: Tuning,
+    /// Also write the tuning to the host's config, so it's applied at boot.
+    pub persist_tuning: bool,
+    /// How many devices to prepare at once.
+    pub max_parallel_devices: usize,
+    /// Priority of our swap devices. With the same priority,
+    /// the kernel spreads pages across all of them.
+    pub swap_priority: i32,
+    pub swap_discard: SwapDiscard,
+    /// If set, swap goes on dm-crypt mappings over the devices, with random keys.
+    pub encryption: Option<CryptConfig>,
+    /// If set, a compressed swap device in RAM is added in front of the disks.
+    pub zram: Option<ZramConfig>,
+    /// If set, swap goes on a logical volume of this size, striped across the disks,
+    /// instead of taking the whole disks. The rest of the volume group is left free.
+    pub swap_size: Option<SwapSize>,
+    /// Volume group to create the swap logical volume in, with `swap_size`.
+    pub vg_name: String,
 }
 impl<D: DiskDetectorTrait> SwapController<D> {
-    pub async fn setup(&self) {
+    pub async fn setup(&self) -> Result<()> {
         info!("Starting NVMe disk configuration with swap...");
-        let devices = self.disk_detector.detect_devices();
-        for device in &devices {
-            if !self.is_existing_swap(device) {
-                info!("Configuring swap on {device}");
-                self.mkswap(device);
-                self.swapon(device);
+        if let Some(zram) = &self.zram
+            && zram.priority <= self.swap_priority
+        {
+            return Err(Error::Config(format!(
+                "zram priority {} must be higher than the disks' swap priority {}",
+                zram.priority, self.swap_priority
+            )));
+        }
+        let mut swap_devices = match &self.swap_size {
+            Some(size) => vec![self.setup_volume(size).await?],
+            None => {
+                let devices = self.disk_detector.detect_devices().await?;
+                stream::iter(&devices)
+                    .map(|device| self.setup_device(device))
+                    .buffer_unordered(self.max_parallel_devices.max(1))
+                    .try_collect()
+                    .await?
             }
+        };
+        if let Some(zram) = &self.zram {
+            swap_devices.push(zram.setup(&self.commander).await?);
         }
 
-        if self.apply_sysctls {
-            info!("Setting sysctls to improve swap performance and safety");
-            self.sysctl("vm.swappiness", self.vm_swappiness);
-            self.sysctl("vm.min_free_kbytes", self.vm_min_free_kbytes);
-            self.sysctl("vm.watermark_scale_factor", self.vm_watermark_scale_factor);
+        if !self.tuning.is_empty() {
+            info!("Tuning the kernel to improve swap performance and safety");
+            Tuner::new(&self.commander).apply(&self.tuning).await?;
+        }
+        if self.persist_tuning {
+            Tuner::new(&self.commander).persist(&self.tuning)?;
         }
 
         if self.bottlerocket_enable_swap {
             info!("Enabling swap with the Bottlerocket apiclient");
-            self.commander.check_output(&[
-                "apiclient",
-                "set",
-                "settings.kubernetes.memory-swap-behavior=LimitedSwap",
-            ]);
+            self.apiclient_set(&self.kubelet_settings.apiclient_settings())
+                .await?;
         }
 
         if self.hack_restart_kubelet_enable_swap {
             info!("Hackily enabling swap by modifying the Kubelet config and restarting it.");
-            match self.cloud_provider {
-                CloudProvider::Gcp => {
-                    self.update_kubelet_config("/host/home/kubernetes/kubelet-config.yaml");
-                }
-                CloudProvider::Azure => {
-                    // Azure doesn't use a kubelet config file by default,
-                    // and there isn't a command line flag to enable LimitedSwap.
-                    self.update_kubelet_config("/host/var/lib/kubelet/config.yaml");
-                    // Azure does reference an env var for the kubelet config file args,
-                    // but it isn't set initially.
-                    fs::write(
-                        "/host/etc/systemd/system/kubelet.service.d/99-enable-swap.conf",
-                        r#"[Service]
-Environment="KUBELET_CONFIG_FILE_FLAGS=--config /var/lib/kubelet/config.yaml""#,
-                    )
-                    .unwrap();
-                }
-                _ => panic!(
-                    "Hack enabling swap by restarting the kubelet is not supported for cloud provider: {:?}",
-                    self.cloud_provider
-                ),
+            let backup = KubeletConfig {
+                commander: &self.commander,
+                cloud_provider: self.cloud_provider,
             }
+            .apply(&swap_settings(&self.kubelet_settings))
+            .await?;
 
-            self.commander
-                .check_output(&["chroot", "/host", "systemctl", "daemon-reload"]);
-
-            self.commander.check_output(&[
-                "chroot",
-                "/host",
-                "systemctl",
-                "restart",
-                "kubelet.service",
-            ]);
+            if let Err(e) = self.restart_kubelet_and_wait().await {
+                error!("Kubelet didn't recover, restoring its original config: {e}");
+                let rollback = match backup.restore(&self.commander) {
+                    Ok(()) => self.restart_kubelet_and_wait().await,
+                    Err(rollback) => Err(rollback),
+                };
+                return Err(match rollback {
+                    Ok(()) => Error::RolledBack(Box::new(e)),
+                    Err(rollback) => Error::RollbackFailed {
+                        cause: Box::new(e),
+                        rollback: Box::new(rollback),
+                    },
+                });
+            }
         }
 
+        self.verify(&swap_devices).await?;
+
         info!("Swap setup completed successfully");
         if self.remove_taint {
             remove_taint(
+                &self.commander,
                 self.node_name.as_ref().expect("clap enforced"),
                 &self.taint_key,
             )
-            .await;
+            .await?;
+        }
+        Ok(())
+    }
+
+    async fn restart_kubelet_and_wait(&self) -> Result<()> {
+        self.restart_kubelet().await?;
+        wait_until_healthy(
+            &self.commander,
+            HEALTHZ_ADDR,
+            self.node_name.as_deref(),
+            self.kubelet_health_timeout,
+        )
+        .await
+    }
+
+    async fn restart_kubelet(&self) -> Result<()> {
+        match Systemd::connect(&self.commander).await {
+            Ok(systemd) => {
+                systemd.reload().await?;
+                systemd.restart_unit("kubelet.service").await
+            }
+            Err(e) => {
+                // We can still get by with systemctl, but can't tell if the restart worked.
+                warn!("Failed to connect to systemd, falling back to systemctl: {e}");
+                let host = self.commander.on_host();
+                host.idempotent_output(&["systemctl", "daemon-reload"])
+                    .await?;
+                host.idempotent_output(&["systemctl", "restart", "kubelet.service"])
+                    .await?;
+                Ok(())
+            }
+        }
+    }
+
+    /// Checks that the kernel is using our swap devices,
+    /// and that the kubelet is running with swap enabled if we changed it.
+    async fn verify(&self, devices: &[String]) -> Result<()> {
+        if !self
+            .commander
+            .should_perform("verify swap is in effect".to_owned())
+        {
+            return Ok(());
+        }
+        let swaps = std::fs::read_to_string("/proc/swaps")
+            .map_err(|e| Error::io("Failed to read /proc/swaps", e))?;
+        let meminfo = std::fs::read_to_string("/proc/meminfo")
+            .map_err(|e| Error::io("Failed to read /proc/meminfo", e))?;
+        check_swap_total(devices, &active_swaps(&swaps), &meminfo)
+            .map_err(Error::SwapNotInEffect)?;
+
+        if !self.bottlerocket_enable_swap && !self.hack_restart_kubelet_enable_swap {
+            return Ok(());
+        }
+        let Some(node_name) = &self.node_name else {
+            warn!("Can't check the kubelet's swap behavior without --node-name");
+            return Ok(());
+        };
+        // The kubelet may still be restarting, particularly after Bottlerocket's apiclient.
+        let expected = self.kubelet_settings.swap_behavior.as_str();
+        let start = tokio::time::Instant::now();
+        loop {
+            let config = match running_config(node_name).await {
+                Ok(Some(config)) => Ok(config),
+                Ok(None) => {
+                    warn!(
+                        "Not allowed to get nodes/proxy, so trusting the kubelet settings we applied"
+                    );
+                    return Ok(());
+                }
+                Err(e) => Err(e),
+            };
+            let behavior = match &config {
+                Ok(config) => config["memorySwap"]["swapBehavior"]
+                    .as_str()
+                    .unwrap_or("NoSwap")
+                    .to_owned(),
+                Err(e) => e.to_string(),
+            };
+            if behavior == expected {
+                info!("Kubelet is running with swap behavior {behavior}");
+                return Ok(());
+            }
+            if start.elapsed() >= self.kubelet_health_timeout {
+                return Err(match config {
+                    Ok(_) => Error::SwapNotInEffect(format!(
+                        "kubelet is running with swap behavior {behavior}, not {expected}"
+                    )),
+                    Err(e) => e,
+                });
+            }
+            warn!("Kubelet swap behavior isn't {expected} yet: {behavior}");
+            tokio::time::sleep(Duration::from_secs(5)).await;
         }
     }
 
-    fn mkswap(&self, device: &str) {
-        self.commander.check_output(&["mkswap", device]);
+    /// Enables swap on the device, returning the path swap is on.
+    async fn setup_device(&self, device: &str) -> Result<String> {
+        let swap_device = match &self.encryption {
+            Some(crypt) => crypt.open(&self.commander, device).await?,
+            None => device.to_owned(),
+        };
+        self.enable_swap(&swap_device).await?;
+        Ok(swap_device)
+    }
+
+    /// Enables swap on a logical volume of the given size, striped across the disks,
+    /// returning its path.
+    async fn setup_volume(&self, size: &SwapSize) -> Result<String> {
+        let size_args = size.lvcreate_args(mem_total_kib()?);
+        info!("Sizing swap as {size:?} with {size_args:?}");
+        let swap_device = LvmController {
+            commander: self.commander.clone(),
+            disk_detector: &self.disk_detector,
+            node_name: None,
+            taint_key: self.taint_key.clone(),
+            remove_taint: false,
+            vg_name: self.vg_name.clone(),
+            cache: None,
+            max_parallel_devices: self.max_parallel_devices,
+            encryption: self.encryption.clone(),
+        }
+        .setup_striped_volume(SWAP_LV_NAME, &size_args)
+        .await?;
+        self.enable_swap(&swap_device).await?;
+        Ok(swap_device)
     }
 
-    fn swapon(&self, device: &str) {
-        self.commander.check_output(&["swapon", device]);
+    async fn enable_swap(&self, swap_device: &str) -> Result<()> {
+        if !self.is_existing_swap(swap_device).await? {
+            info!("Configuring swap on {swap_device}");
+            self.mkswap(swap_device).await?;
+            self.swapon(swap_device).await?;
+        }
+        Ok(())
+    }
+
+    async fn mkswap(&self, device: &str) -> Result<()> {
+        // Alpine's busybox mkswap only takes the short option.
+        self.commander
+            .mutating_output(&["mkswap", "-L", SWAP_LABEL, device])
+            .await?;
+        Ok(())
     }
 
-    fn is_existing_swap(&self, device: &str) -> bool {
-        // /proc/swaps has contents like:
-        // Filename				Type		Size		Used		Priority
-        // /nvme0n1                                partition	393215996	0		-2
-        std::fs::read_to_string("/proc/swaps")
-            .expect("failed to read /proc/swaps")
-            .trim()
-            .lines()
-            .skip(1)
-            .map(|line| line.split_whitespace().next().unwrap())
+    async fn swapon(&self, device: &str) -> Result<()> {
+        let args = swapon_args(device, self.swap_priority, self.swap_discard);
+        let args: Vec<&str> = args.iter().map(String::as_str).collect();
+        self.commander.mutating_output(&args).await?;
+        Ok(())
+    }
+
+    async fn is_existing_swap(&self, device: &str) -> Result<bool> {
+        let swaps = std::fs::read_to_string("/proc/swaps")
+            .map_err(|e| Error::io("Failed to read /proc/swaps", e))?;
+        Ok(active_swaps(&swaps)
+            .iter()
             // /proc/swaps is inconsistent in how it reports things,
             // sometimes leaving off the /dev at the beginning of the path.
-            .any(|line| device.ends_with(line))
+            .any(|(filename, _)| device.ends_with(filename.as_str())))
     }
 
-    fn sysctl(&self, key: &str, value: usize) {
-        self.commander
-            .check_output(&["sysctl", &format!("{key}={value}")]);
+    /// Changes Bottlerocket settings, given as `key=value`.
+    async fn apiclient_set(&self, settings: &[String]) -> Result<()> {
+        let mut args = vec!["apiclient", "set"];
+        args.extend(settings.iter().map(String::as_str));
+        self.commander.idempotent_output(&args).await?;
+        Ok(())
     }
+}
+
+/// Alpine's busybox swapon only takes short options,
+/// with the discard policy attached to `-d`, which util-linux also accepts.
+pub(crate) fn swapon_args(device: &str, priority: i32, discard: SwapDiscard) -> Vec<String> {
+    let mut args = vec!["swapon".to_owned(), "-p".to_owned(), priority.to_string()];
+    match discard {
+        SwapDiscard::Once => args.push("-donce".to_owned()),
+        SwapDiscard::Pages => args.push("-dpages".to_owned()),
+        SwapDiscard::None => {}
+    }
+    args.push(device.to_owned());
+    args
+}
 
-    fn update_kubelet_config(&self, path: &str) {
-        // Read existing configuration, if any.
-        let mut kubelet_config: BTreeMap<String, Value> = match fs::read(path) {
-            Ok(data) => serde_yaml::from_slice(&data).unwrap(),
-            Err(e) if e.kind() == ErrorKind::NotFound => BTreeMap::new(),
-            Err(e) => panic!("failed to read kubelet config {path}: {e:?}"),
+/// Parses the filenames and sizes in KiB of active swap devices from /proc/swaps.
+fn active_swaps(swaps: &str) -> Vec<(String, u64)> {
+    parse_swaps(swaps, |dm| {
+        std::fs::read_to_string(format!("/sys/block/{dm}/dm/name"))
+            .ok()
+            .map(|name| name.trim().to_owned())
+    })
+}
+
+/// Parses /proc/swaps. Device-mapper devices are listed by their kernel names,
+/// so they're translated to /dev/mapper paths using the `dm_name` lookup.
+fn parse_swaps(swaps: &str, dm_name: impl Fn(&str) -> Option<String>) -> Vec<(String, u64)> {
+    // /proc/swaps has contents like:
+    // Filename				Type		Size		Used		Priority
+    // /nvme0n1                                partition	393215996	0		-2
+    // /dev/dm-0                               partition	393215996	0		-3
+    swaps
+        .trim()
+        .lines()
+        .skip(1)
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            let filename = fields.next()?;
+            let size = fields.nth(1)?.parse().ok()?;
+            let kernel_name = filename.rsplit('/').next().unwrap_or(filename);
+            let filename = match kernel_name.strip_prefix("dm-").and(dm_name(kernel_name)) {
+                Some(name) => mapping_path(&name),
+                None => filename.to_owned(),
+            };
+            Some((filename, size))
+        })
+        .collect()
+}
+
+/// Checks that every device is an active swap device, and that the kernel's
+/// total swap includes all of them.
+fn check_swap_total(
+    devices: &[String],
+    active: &[(String, u64)],
+    meminfo: &str,
+) -> std::result::Result<(), String> {
+    let mut expected = 0;
+    for device in devices {
+        let Some((_, size)) = active
+            .iter()
+            .find(|(filename, _)| device.ends_with(filename.as_str()))
+        else {
+            return Err(format!("{device} is not an active swap device"));
         };
+        expected += size;
+    }
+    // /proc/swaps sizes are in KiB, like /proc/meminfo.
+    let swap_total = meminfo_kib(meminfo, "SwapTotal").ok_or("no SwapTotal in /proc/meminfo")?;
+    if swap_total < expected {
+        return Err(format!(
+            "SwapTotal is {swap_total} kB, but our devices add up to {expected} kB"
+        ));
+    }
+    Ok(())
+}
+
+/// MemTotal from /proc/meminfo, in KiB.
+pub(crate) fn mem_total_kib() -> Result<u64> {
+    let meminfo = std::fs::read_to_string("/proc/meminfo")
+        .map_err(|e| Error::io("Failed to read /proc/meminfo", e))?;
+    meminfo_kib(&meminfo, "MemTotal")
+        .ok_or_else(|| Error::parse("Failed to read /proc/meminfo", "no MemTotal"))
+}
+
+/// A value from /proc/meminfo, in KiB.
+fn meminfo_kib(meminfo: &str, key: &str) -> Option<u64> {
+    meminfo
+        .lines()
+        .find_map(|line| line.strip_prefix(key)?.strip_prefix(':'))
+        .and_then(|value| value.trim().trim_end_matches("kB").trim().parse().ok())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::PlanStep;
+    use crate::detect::StaticDisks;
+    use crate::test::TestEnv;
+
+    const SWAPS: &str = "Filename\t\t\t\tType\t\tSize\t\tUsed\t\tPriority
+/dev/nvme1n1                            partition\t393215996\t0\t\t-2
+/nvme2n1                                partition\t393215996\t0\t\t-3
+";
+
+    fn devices(devices: &[&str]) -> Vec<String> {
+        devices.iter().map(|device| device.to_string()).collect()
+    }
 
-        // Ensure we have the type information, in case we're making a new file.
-        kubelet_config
-            .entry("kind".to_owned())
-            .or_insert(Value::String("KubeletConfiguration".to_owned()));
-        kubelet_config
-            .entry("apiVersion".to_owned())
-            .or_insert(Value::String("kubelet.config.k8s.io/v1beta1".to_owned()));
-
-        // Enable swap.
-        kubelet_config.insert("failSwapOn".to_owned(), Value::Bool(false));
-        let mut memory_swap = Mapping::new();
-        memory_swap.insert(
-            Value::String("swapBehavior".to_owned()),
-            Value::String("LimitedSwap".to_owned()),
+    #[test]
+    fn test_apiclient_settings() {
+        let settings = KubeletSwapSettings {
+            swap_behavior: SwapBehavior::NoSwap,
+            eviction_hard_memory: Some("500Mi".to_owned()),
+            kube_reserved_memory: Some("1Gi".to_owned()),
+            ..Default::default()
+        };
+        assert_eq!(
+            settings.apiclient_settings(),
+            vec![
+                "settings.kubernetes.memory-swap-behavior=NoSwap",
+                r#"settings.kubernetes.eviction-hard."memory.available"=500Mi"#,
+                "settings.kubernetes.kube-reserved.memory=1Gi",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_swapon_args() {
+        assert_eq!(
+            swapon_args("/dev/nvme1n1", 10, SwapDiscard::None),
+            vec!["swapon", "-p", "10", "/dev/nvme1n1"]
         );
-        kubelet_config.insert("memorySwap".to_owned(), Value::Mapping(memory_swap));
+        assert_eq!(
+            swapon_args("/dev/nvme1n1", 10, SwapDiscard::Pages),
+            vec!["swapon", "-p", "10", "-dpages", "/dev/nvme1n1"]
+        );
+    }
 
-        // Write the updates.
-        fs::write(path, serde_yaml::to_string(&kubelet_config).unwrap()).unwrap();
+    #[test]
+    fn test_parse_swaps() {
+        let swaps =
+            format!("{SWAPS}/dev/dm-0 partition\t1000\t0\t-4\n/dev/dm-1 partition\t2000\t0\t-5\n");
+        let dm_name = |dm: &str| (dm == "dm-0").then(|| "ephemeral-crypt-nvme3n1".to_owned());
+        assert_eq!(
+            parse_swaps(&swaps, dm_name),
+            vec![
+                ("/dev/nvme1n1".to_owned(), 393215996),
+                ("/nvme2n1".to_owned(), 393215996),
+                ("/dev/mapper/ephemeral-crypt-nvme3n1".to_owned(), 1000),
+                ("/dev/dm-1".to_owned(), 2000),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_check_swap_total() {
+        let active = parse_swaps(SWAPS, |_| None);
+        let meminfo =
+            "MemTotal:       16000000 kB\nSwapTotal:      786431992 kB\nSwapFree: 786431992 kB\n";
+        let ours = devices(&["/dev/nvme1n1", "/dev/nvme2n1"]);
+        assert_eq!(check_swap_total(&ours, &active, meminfo), Ok(()));
+
+        assert_eq!(
+            check_swap_total(&devices(&["/dev/nvme3n1"]), &active, meminfo),
+            Err("/dev/nvme3n1 is not an active swap device".to_owned())
+        );
+        assert_eq!(
+            check_swap_total(&ours, &active, "SwapTotal: 393215996 kB\n"),
+            Err("SwapTotal is 393215996 kB, but our devices add up to 786431992 kB".to_owned())
+        );
+    }
+
+    #[test]
+    fn test_swap_size() {
+        assert_eq!(
+            parse_swap_size("2x-memory"),
+            Ok(SwapSize::MemoryMultiple(2.0))
+        );
+        assert_eq!(parse_swap_size("200GiB"), Ok(SwapSize::Bytes(200 << 30)));
+        assert_eq!(parse_swap_size("200G"), Ok(SwapSize::Bytes(200 << 30)));
+        assert_eq!(parse_swap_size("512Mi"), Ok(SwapSize::Bytes(512 << 20)));
+        assert_eq!(parse_swap_size("50%"), Ok(SwapSize::Percent(50)));
+        for invalid in ["", "0x-memory", "0G", "101%", "200GB", "G", "-1G"] {
+            assert!(parse_swap_size(invalid).is_err(), "{invalid}");
+        }
+
+        assert_eq!(
+            SwapSize::MemoryMultiple(1.5).lvcreate_args(16000000),
+            vec!["--size", "24000000k"]
+        );
+        assert_eq!(
+            SwapSize::Bytes(200 << 30).lvcreate_args(16000000),
+            vec!["--size", "209715200k"]
+        );
+        assert_eq!(
+            SwapSize::Percent(50).lvcreate_args(16000000),
+            vec!["--extents", "50%VG"]
+        );
+        assert_eq!(
+            meminfo_kib("MemTotal: 16000000 kB\n", "MemTotal"),
+            Some(16000000)
+        );
+        assert_eq!(meminfo_kib("MemTotalX: 1 kB\n", "MemTotal"), None);
+    }
+
+    #[tokio::test]
+    async fn test_setup_volume_dry_run() {
+        let test_env = TestEnv::new();
+        // Like LVM, fail when asked about a volume group that doesn't exist.
+        for (command, report) in [("vgs", "vg"), ("lvs", "lv")] {
+            test_env.mock_script(
+                command,
+                &format!(
+                    r#"for arg in "$@"; do [ "$arg" != instance-store-vg ] || exit 5; done
+echo '{{"report": [{{"{report}": []}}]}}'"#
+                ),
+            );
+        }
+        test_env.mock("pvs", 0, r#"{"report": [{"pv": []}]}"#);
+        let commander = Commander {
+            plan: Some(Default::default()),
+            ..test_env.commander.clone()
+        };
+        let size_args = SwapSize::Percent(50).lvcreate_args(0);
+        let device = LvmController {
+            commander: commander.clone(),
+            disk_detector: StaticDisks(devices(&["/dev/nvme1n1", "/dev/nvme2n1"])),
+            node_name: None,
+            taint_key: "disk-unconfigured".to_owned(),
+            remove_taint: false,
+            vg_name: "instance-store-vg".to_owned(),
+            cache: None,
+            max_parallel_devices: 1,
+            encryption: None,
+        }
+        .setup_striped_volume(SWAP_LV_NAME, &size_args)
+        .await
+        .unwrap();
+        assert_eq!(device, "/dev/mapper/instance--store--vg-swap");
+        let command = |args: &[&str]| PlanStep::Command {
+            args: args.iter().map(|arg| arg.to_string()).collect(),
+        };
+        assert_eq!(
+            commander.plan(),
+            vec![
+                command(&["pvcreate", "-f", "/dev/nvme1n1"]),
+                command(&["pvcreate", "-f", "/dev/nvme2n1"]),
+                command(&[
+                    "vgcreate",
+                    "--addtag",
+                    "ephemeral-storage-setup",
+                    "instance-store-vg",
+                    "/dev/nvme1n1",
+                    "/dev/nvme2n1",
+                ]),
+                command(&[
+                    "lvcreate",
+                    "--yes",
+                    "--name",
+                    "swap",
+                    "--extents",
+                    "50%VG",
+                    "--stripes",
+                    "2",
+                    "instance-store-vg",
+                    "/dev/nvme1n1",
+                    "/dev/nvme2n1",
+                ]),
+            ]
+        );
     }
 }
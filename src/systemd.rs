@@ -0,0 +1,361 @@
+use std::time::Duration;
+
+use futures::StreamExt;
+use tokio::net::UnixStream;
+use tracing::{info, warn};
+use zbus::proxy::CacheProperties;
+use zbus::zvariant::OwnedObjectPath;
+use zbus::{Connection, proxy};
+
+use crate::Commander;
+use crate::error::{Error, Result};
+
+/// systemd's own socket, which speaks D-Bus directly without a bus daemon.
+const PRIVATE_SOCKET_PATH: &str = "/run/systemd/private";
+/// The system bus, for hosts where we can't use the private socket.
+const SYSTEM_BUS_SOCKET_PATH: &str = "/run/dbus/system_bus_socket";
+
+/// How long to wait for a unit to become active after its job finished.
+const ACTIVE_STATE_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[proxy(
+    interface = "org.freedesktop.systemd1.Manager",
+    default_service = "org.freedesktop.systemd1",
+    default_path = "/org/freedesktop/systemd1"
+)]
+trait Manager {
+    fn subscribe(&self) -> zbus::Result<()>;
+
+    fn reload(&self) -> zbus::Result<()>;
+
+    fn restart_unit(&self, name: &str, mode: &str) -> zbus::Result<OwnedObjectPath>;
+
+    fn get_unit(&self, name: &str) -> zbus::Result<OwnedObjectPath>;
+
+    #[zbus(signal)]
+    fn job_removed(
+        &self,
+        id: u32,
+        job: zbus::zvariant::ObjectPath<'_>,
+        unit: &str,
+        result: &str,
+    ) -> zbus::Result<()>;
+}
+
+#[proxy(
+    interface = "org.freedesktop.systemd1.Unit",
+    default_service = "org.freedesktop.systemd1"
+)]
+trait Unit {
+    #[zbus(property)]
+    fn active_state(&self) -> zbus::Result<String>;
+}
+
+/// Talks to the host's systemd over D-Bus.
+///
+/// Unlike running systemctl, this doesn't need anything installed on the host,
+/// and tells us whether the jobs we start actually succeed.
+pub(crate) struct Systemd {
+    commander: Commander,
+    // Not connected in a dry run.
+    manager: Option<ManagerProxy<'static>>,
+}
+
+impl Systemd {
+    /// Connects to the host's systemd, through its private socket if possible,
+    /// and otherwise through the system bus.
+    pub(crate) async fn connect(commander: &Commander) -> Result<Self> {
+        if commander.is_dry_run() {
+            return Ok(Systemd {
+                commander: commander.clone(),
+                manager: None,
+            });
+        }
+        let private_socket = commander.host_path(PRIVATE_SOCKET_PATH);
+        let connection = match connect_to(&private_socket, true).await {
+            Ok(connection) => connection,
+            Err(e) => {
+                warn!("{e}, falling back to the system bus");
+                connect_to(&commander.host_path(SYSTEM_BUS_SOCKET_PATH), false).await?
+            }
+        };
+        Self::with_connection(commander, &connection).await
+    }
+
+    async fn with_connection(commander: &Commander, connection: &Connection) -> Result<Self> {
+        let manager = ManagerProxy::new(connection)
+            .await
+            .map_err(|e| Error::systemd("Failed to create systemd manager proxy", e))?;
+        // Without this, systemd doesn't send us signals about jobs.
+        manager
+            .subscribe()
+            .await
+            .map_err(|e| Error::systemd("Failed to subscribe to systemd signals", e))?;
+        Ok(Systemd {
+            commander: commander.clone(),
+            manager: Some(manager),
+        })
+    }
+
+    /// Reloads unit files, like `systemctl daemon-reload`.
+    pub(crate) async fn reload(&self) -> Result<()> {
+        if !self
+            .commander
+            .should_perform("reload systemd unit files".to_owned())
+        {
+            return Ok(());
+        }
+        info!("Reloading systemd unit files");
+        self.manager()
+            .reload()
+            .await
+            .map_err(|e| Error::systemd("Failed to reload systemd", e))
+    }
+
+    /// Restarts the unit, waiting for the restart job to finish
+    /// and for the unit to become active.
+    pub(crate) async fn restart_unit(&self, unit: &str) -> Result<()> {
+        if !self.commander.should_perform(format!("restart {unit}")) {
+            return Ok(());
+        }
+        info!("Restarting {unit}");
+        let manager = self.manager();
+        // Start listening before creating the job, so we can't miss its removal.
+        let mut jobs_removed = manager
+            .receive_job_removed()
+            .await
+            .map_err(|e| Error::systemd("Failed to listen for systemd jobs", e))?;
+        let job = manager
+            .restart_unit(unit, "replace")
+            .await
+            .map_err(|e| Error::systemd(format!("Failed to restart {unit}"), e))?;
+
+        let wait_for_job = async {
+            while let Some(signal) = jobs_removed.next().await {
+                let args = signal
+                    .args()
+                    .map_err(|e| Error::systemd("Invalid JobRemoved signal", e))?;
+                if args.job == *job {
+                    return Ok(args.result.to_owned());
+                }
+            }
+            Err(Error::SystemdJob {
+                unit: unit.to_owned(),
+                result: "disconnected".to_owned(),
+            })
+        };
+        let result = match self.commander.policy.timeout {
+            Some(timeout) => tokio::time::timeout(timeout, wait_for_job)
+                .await
+                .unwrap_or_else(|_| Ok(format!("still running after {timeout:?}")))?,
+            None => wait_for_job.await?,
+        };
+        if result != "done" {
+            return Err(Error::SystemdJob {
+                unit: unit.to_owned(),
+                result,
+            });
+        }
+        self.wait_until_active(unit).await
+    }
+
+    async fn wait_until_active(&self, unit: &str) -> Result<()> {
+        let manager = self.manager();
+        let path = manager
+            .get_unit(unit)
+            .await
+            .map_err(|e| Error::systemd(format!("Failed to get {unit}"), e))?;
+        let unit_proxy = UnitProxy::builder(manager.inner().connection())
+            .path(path)
+            .map_err(|e| Error::systemd(format!("Invalid path for {unit}"), e))?
+            .cache_properties(CacheProperties::No)
+            .build()
+            .await
+            .map_err(|e| Error::systemd(format!("Failed to create proxy for {unit}"), e))?;
+
+        let start = tokio::time::Instant::now();
+        loop {
+            let state = unit_proxy
+                .active_state()
+                .await
+                .map_err(|e| Error::systemd(format!("Failed to get state of {unit}"), e))?;
+            match state.as_str() {
+                "active" => {
+                    info!("{unit} is active");
+                    return Ok(());
+                }
+                "activating" | "reloading" if start.elapsed() < ACTIVE_STATE_TIMEOUT => {
+                    tokio::time::sleep(Duration::from_secs(1)).await;
+                }
+                _ => {
+                    return Err(Error::SystemdJob {
+                        unit: unit.to_owned(),
+                        result: state,
+                    });
+                }
+            }
+        }
+    }
+
+    fn manager(&self) -> &ManagerProxy<'static> {
+        self.manager
+            .as_ref()
+            .expect("only connected when not a dry run")
+    }
+}
+
+async fn connect_to(path: &str, p2p: bool) -> Result<Connection> {
+    let stream = UnixStream::connect(path)
+        .await
+        .map_err(|e| Error::io(format!("Failed to connect to {path}"), e))?;
+    let builder = zbus::connection::Builder::unix_stream(stream);
+    // systemd's private socket has no bus daemon to say hello to.
+    let builder = if p2p { builder.p2p() } else { builder };
+    builder
+        .build()
+        .await
+        .map_err(|e| Error::systemd(format!("Failed to connect to D-Bus at {path}"), e))
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::{Arc, Mutex};
+
+    use tokio::net::UnixListener;
+    use zbus::object_server::SignalEmitter;
+    use zbus::zvariant::ObjectPath;
+    use zbus::{Guid, interface};
+
+    use super::*;
+    use crate::error::Error;
+    use crate::test::TestEnv;
+
+    const UNIT_PATH: &str = "/org/freedesktop/systemd1/unit/kubelet_2eservice";
+    const JOB_PATH: &str = "/org/freedesktop/systemd1/job/42";
+
+    /// Stands in for systemd's manager, recording the calls made to it.
+    struct FakeManager {
+        calls: Arc<Mutex<Vec<String>>>,
+        job_result: &'static str,
+    }
+
+    #[interface(name = "org.freedesktop.systemd1.Manager")]
+    impl FakeManager {
+        fn subscribe(&self) {
+            self.calls.lock().unwrap().push("Subscribe".to_owned());
+        }
+
+        fn reload(&self) {
+            self.calls.lock().unwrap().push("Reload".to_owned());
+        }
+
+        async fn restart_unit(
+            &self,
+            name: &str,
+            mode: &str,
+            #[zbus(signal_emitter)] emitter: SignalEmitter<'_>,
+        ) -> zbus::fdo::Result<OwnedObjectPath> {
+            self.calls
+                .lock()
+                .unwrap()
+                .push(format!("RestartUnit {name} {mode}"));
+            let job = ObjectPath::from_static_str_unchecked(JOB_PATH);
+            // A job for some other unit, which should be ignored.
+            Self::job_removed(
+                &emitter,
+                41,
+                ObjectPath::from_static_str_unchecked("/org/freedesktop/systemd1/job/41"),
+                "other.service",
+                "failed",
+            )
+            .await?;
+            Self::job_removed(&emitter, 42, job.clone(), name, self.job_result).await?;
+            Ok(job.into())
+        }
+
+        fn get_unit(&self, _name: &str) -> OwnedObjectPath {
+            ObjectPath::from_static_str_unchecked(UNIT_PATH).into()
+        }
+
+        #[zbus(signal)]
+        async fn job_removed(
+            emitter: &SignalEmitter<'_>,
+            id: u32,
+            job: ObjectPath<'_>,
+            unit: &str,
+            result: &str,
+        ) -> zbus::Result<()>;
+    }
+
+    struct FakeUnit;
+
+    #[interface(name = "org.freedesktop.systemd1.Unit")]
+    impl FakeUnit {
+        #[zbus(property)]
+        fn active_state(&self) -> String {
+            "active".to_owned()
+        }
+    }
+
+    /// Serves a fake systemd on a socket, like its private socket.
+    /// Returns the server and client ends of the connection,
+    /// and the calls made to the fake systemd.
+    async fn serve_fake_systemd(
+        test_env: &TestEnv,
+        job_result: &'static str,
+    ) -> (Connection, Connection, Arc<Mutex<Vec<String>>>) {
+        let path = test_env.temp_dir.path().join("private");
+        let listener = UnixListener::bind(&path).unwrap();
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let manager = FakeManager {
+            calls: Arc::clone(&calls),
+            job_result,
+        };
+        let server = async {
+            let (stream, _) = listener.accept().await.unwrap();
+            zbus::connection::Builder::unix_stream(stream)
+                .server(Guid::generate())
+                .unwrap()
+                .p2p()
+                .serve_at("/org/freedesktop/systemd1", manager)
+                .unwrap()
+                .serve_at(UNIT_PATH, FakeUnit)
+                .unwrap()
+                .build()
+                .await
+                .unwrap()
+        };
+        let (server, client) = tokio::join!(server, connect_to(path.to_str().unwrap(), true));
+        (server, client.unwrap(), calls)
+    }
+
+    #[tokio::test]
+    async fn test_restart_unit() {
+        let test_env = TestEnv::new();
+        let (_server, connection, calls) = serve_fake_systemd(&test_env, "done").await;
+        let systemd = Systemd::with_connection(&test_env.commander, &connection)
+            .await
+            .unwrap();
+        systemd.reload().await.unwrap();
+        systemd.restart_unit("kubelet.service").await.unwrap();
+        assert_eq!(
+            *calls.lock().unwrap(),
+            vec![
+                "Subscribe".to_owned(),
+                "Reload".to_owned(),
+                "RestartUnit kubelet.service replace".to_owned(),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_restart_unit_failed() {
+        let test_env = TestEnv::new();
+        let (_server, connection, _) = serve_fake_systemd(&test_env, "failed").await;
+        let systemd = Systemd::with_connection(&test_env.commander, &connection)
+            .await
+            .unwrap();
+        let result = systemd.restart_unit("kubelet.service").await;
+        assert!(matches!(result, Err(Error::SystemdJob { result, .. }) if result == "failed"));
+    }
+}
@@ -0,0 +1,586 @@
+use serde::Deserialize;
+use tracing::{info, warn};
+
+use crate::Commander;
+use crate::crypt::{self, our_mapping};
+use crate::detect::{LsblkBlockDevice, lsblk};
+use crate::error::{Error, Result};
+use crate::lvm::{ORIGIN_PV_TAG, VG_TAG, has_tag};
+use crate::partition::is_our_partition;
+use crate::swap::{SWAP_LABEL, is_our_swap};
+use crate::tuning::remove_persisted;
+use crate::zram;
+
+#[derive(Deserialize)]
+struct LvmReportWrapper {
+    report: Vec<LvmReport>,
+}
+
+#[derive(Deserialize)]
+struct LvmReport {
+    vg: Option<Vec<VgReport>>,
+    pv: Option<Vec<PvReport>>,
+    lv: Option<Vec<LvReport>>,
+}
+
+#[derive(Deserialize)]
+struct VgReport {
+    vg_name: String,
+    // Comma separated list of tags.
+    vg_tags: String,
+}
+
+#[derive(Deserialize)]
+struct PvReport {
+    pv_name: String,
+    vg_name: String,
+    // Comma separated list of tags.
+    pv_tags: String,
+}
+
+#[derive(Deserialize)]
+struct LvReport {
+    lv_name: String,
+    // Device mapper path of the LV, as lsblk reports it.
+    lv_dm_path: String,
+    // Segment type of the LV (linear, striped, cache, writecache, etc...)
+    segtype: String,
+    // "open" if the device is in use (mounted, swapped on, etc...), otherwise empty.
+    lv_device_open: String,
+}
+
+/// Undoes what the `lvm` and `swap` commands set up.
+///
+/// Only resources we can recognize as our own are touched:
+/// swap devices labeled with [`SWAP_LABEL`] and a volume group tagged with [`VG_TAG`].
+/// We never mount anything ourselves, so there is nothing for us to unmount.
+/// zram devices we swapped on are reset, freeing their memory.
+/// Disks partitioned by the `combined` command have their partition tables removed,
+/// once nothing of ours is left on the partitions.
+/// Persisted kernel tuning is removed, but stays in effect until the next reboot.
+/// Logical volumes that are still in use by someone else are refused instead.
+/// In cache mode, the cache is detached and only the ephemeral physical volumes are removed,
+/// leaving the volume group and the origin on the persistent disk.
+pub struct TeardownController {
+    pub commander: Commander,
+    pub vg_name: String,
+    pub wipe: bool,
+}
+
+impl TeardownController {
+    pub async fn teardown(&self) -> Result<()> {
+        info!("Starting teardown...");
+        let blockdevices = lsblk(
+            &self.commander,
+            &[
+                "--output",
+                "PATH,TYPE,FSTYPE,LABEL,MOUNTPOINT,PARTTYPE,PARTLABEL",
+            ],
+        )
+        .await?;
+        let (mut devices, swapped_off) = self.teardown_swap(&blockdevices).await?;
+        info!("Removing persisted kernel tuning");
+        remove_persisted(&self.commander)?;
+        let physical_volumes = self.teardown_volume_group(&swapped_off).await?;
+        // Encrypted physical volumes were closed, with nothing left to wipe.
+        devices.extend(
+            physical_volumes
+                .iter()
+                .filter(|pv| our_mapping(pv).is_none())
+                .cloned(),
+        );
+        if self.wipe {
+            for device in &devices {
+                info!("Wiping signatures from {device}");
+                self.commander
+                    .mutating_output(&["wipefs", "--all", device])
+                    .await?;
+            }
+        }
+        self.zap_partition_tables(&blockdevices, &physical_volumes)
+            .await?;
+        info!("Teardown completed successfully");
+        Ok(())
+    }
+
+    /// Disables our swap devices, returning the paths of those left to wipe,
+    /// and of the logical volumes we disabled swap on.
+    async fn teardown_swap(
+        &self,
+        blockdevices: &[LsblkBlockDevice],
+    ) -> Result<(Vec<String>, Vec<String>)> {
+        let mut swap_devices = Vec::new();
+        flatten(blockdevices, &mut swap_devices);
+        let mut paths = Vec::new();
+        let mut swapped_off = Vec::new();
+        for device in swap_devices
+            .into_iter()
+            .filter(|device| device.fstype.as_deref() == Some("swap"))
+        {
+            if !is_our_swap(device) {
+                warn!(
+                    "Not touching swap device '{}' because it isn't labeled {SWAP_LABEL}.",
+                    device.path
+                );
+                continue;
+            }
+            if device.mountpoint.as_deref() == Some("[SWAP]") {
+                info!("Disabling swap on {}", device.path);
+                self.commander
+                    .mutating_output(&["swapoff", &device.path])
+                    .await?;
+                if device.type_ == "lvm" {
+                    swapped_off.push(device.path.clone());
+                }
+            }
+            // Closing the mapping discards its key, so there's nothing left to wipe.
+            if let Some(name) = our_mapping(&device.path) {
+                crypt::close(&self.commander, name).await?;
+                continue;
+            }
+            // Removing the volume group removes a swap logical volume along with it.
+            if device.type_ == "lvm" {
+                continue;
+            }
+            // Resetting a zram device frees its memory, with nothing left on a disk.
+            if zram::is_zram(&device.path) {
+                zram::reset(&self.commander, &device.path).await?;
+                continue;
+            }
+            paths.push(device.path.clone());
+        }
+        Ok((paths, swapped_off))
+    }
+
+    /// Removes the partition tables from disks with only our partitions,
+    /// as long as nothing of ours is left on them.
+    async fn zap_partition_tables(
+        &self,
+        blockdevices: &[LsblkBlockDevice],
+        physical_volumes: &[String],
+    ) -> Result<()> {
+        for disk in blockdevices.iter().filter(|device| device.type_ == "disk") {
+            let Some(partitions) = disk.children.as_deref().filter(|c| !c.is_empty()) else {
+                continue;
+            };
+            if !partitions.iter().all(|partition| {
+                is_our_partition(
+                    partition.parttype.as_deref(),
+                    partition.partlabel.as_deref(),
+                )
+            }) {
+                continue;
+            }
+            if let Some(partition) = partitions
+                .iter()
+                .find(|partition| !is_released(partition, physical_volumes))
+            {
+                warn!(
+                    "Not removing the partition table from {} because {} is still in use.",
+                    disk.path, partition.path
+                );
+                continue;
+            }
+            info!("Removing the partition table from {}", disk.path);
+            self.commander
+                .mutating_output(&["sgdisk", "--zap-all", &disk.path])
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Removes our volume group and its physical volumes, returning their paths.
+    ///
+    /// Logical volumes we disabled swap on are expected to still show as open in a dry run.
+    async fn teardown_volume_group(&self, swapped_off: &[String]) -> Result<Vec<String>> {
+        let vgs_report = self.lvm_report(&["vgs", "-o", "vg_name,vg_tags"]).await?;
+        let Some(vg) = vgs_report
+            .vg
+            .unwrap_or_default()
+            .into_iter()
+            .find(|vg| vg.vg_name == self.vg_name)
+        else {
+            info!("Volume group {} does not exist.", self.vg_name);
+            return Ok(Vec::new());
+        };
+        if !has_tag(&vg.vg_tags, VG_TAG) {
+            return Err(Error::Refused(format!(
+                "volume group {} isn't tagged {VG_TAG}",
+                self.vg_name
+            )));
+        }
+
+        let logical_volumes = self
+            .lvm_report(&[
+                "lvs",
+                "-o",
+                "lv_name,lv_dm_path,segtype,lv_device_open",
+                &self.vg_name,
+            ])
+            .await?
+            .lv
+            .unwrap_or_default();
+        let pvs_report = self
+            .lvm_report(&["pvs", "-o", "pv_name,vg_name,pv_tags"])
+            .await?;
+        let (origins, physical_volumes): (Vec<PvReport>, Vec<PvReport>) = pvs_report
+            .pv
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|pv| pv.vg_name == self.vg_name)
+            .partition(|pv| has_tag(&pv.pv_tags, ORIGIN_PV_TAG));
+        let physical_volumes: Vec<String> =
+            physical_volumes.into_iter().map(|pv| pv.pv_name).collect();
+        if !origins.is_empty() {
+            return self
+                .teardown_cache(&logical_volumes, physical_volumes)
+                .await;
+        }
+        // Without the tag, we can't tell the origin from the cache.
+        if logical_volumes.iter().any(is_cached) {
+            return Err(Error::Refused(format!(
+                "volume group {} has a cache, but no physical volume tagged {ORIGIN_PV_TAG}",
+                self.vg_name
+            )));
+        }
+
+        let open_lvs: Vec<&str> = logical_volumes
+            .iter()
+            .filter(|lv| lv.lv_device_open == "open" && !swapped_off.contains(&lv.lv_dm_path))
+            .map(|lv| lv.lv_name.as_str())
+            .collect();
+        if !open_lvs.is_empty() {
+            return Err(Error::Refused(format!(
+                "logical volumes {open_lvs:?} in volume group {} are in use",
+                self.vg_name
+            )));
+        }
+
+        info!("Deactivating logical volumes in {}", self.vg_name);
+        self.commander
+            .idempotent_output(&["vgchange", "--activate", "n", &self.vg_name])
+            .await?;
+        info!("Removing volume group {}", self.vg_name);
+        self.commander
+            .mutating_output(&["vgremove", "--yes", "--force", &self.vg_name])
+            .await?;
+        self.remove_physical_volumes(physical_volumes).await
+    }
+
+    /// Detaches the cache from the origin logical volumes,
+    /// and removes the ephemeral physical volumes from the volume group, returning their paths.
+    async fn teardown_cache(
+        &self,
+        logical_volumes: &[LvReport],
+        physical_volumes: Vec<String>,
+    ) -> Result<Vec<String>> {
+        for lv in logical_volumes.iter().filter(|lv| is_cached(lv)) {
+            // Without --force, any writes still in the cache are flushed to the origin first.
+            info!("Detaching cache from {}", lv.lv_name);
+            let origin = format!("{}/{}", self.vg_name, lv.lv_name);
+            self.commander
+                .mutating_output(&["lvconvert", "--yes", "--uncache", &origin])
+                .await?;
+        }
+        for pv in &physical_volumes {
+            info!("Removing physical volume {pv} from {}", self.vg_name);
+            self.commander
+                .mutating_output(&["vgreduce", &self.vg_name, pv])
+                .await?;
+        }
+        self.remove_physical_volumes(physical_volumes).await
+    }
+
+    /// Removes the physical volumes, closing our mappings under them, and returns their paths.
+    async fn remove_physical_volumes(&self, physical_volumes: Vec<String>) -> Result<Vec<String>> {
+        for pv in &physical_volumes {
+            info!("Removing physical volume {pv}");
+            self.commander
+                .mutating_output(&["pvremove", "--yes", pv])
+                .await?;
+            if let Some(name) = our_mapping(pv) {
+                crypt::close(&self.commander, name).await?;
+            }
+        }
+        Ok(physical_volumes)
+    }
+
+    async fn lvm_report(&self, args: &[&str]) -> Result<LvmReport> {
+        let mut args = args.to_vec();
+        args.extend(["--reportformat", "json"]);
+        let output = self.commander.check_output(&args).await?;
+        let report: LvmReportWrapper = serde_json::from_slice(&output.stdout)
+            .map_err(|e| Error::parse(format!("Failed to deserialize output of '{args:?}'"), e))?;
+        report
+            .report
+            .into_iter()
+            .next()
+            .ok_or_else(|| Error::parse(format!("Empty report from '{args:?}'"), "no reports"))
+    }
+}
+
+fn flatten<'a>(devices: &'a [LsblkBlockDevice], out: &mut Vec<&'a LsblkBlockDevice>) {
+    for device in devices {
+        if let Some(children) = &device.children {
+            flatten(children, out);
+        }
+        out.push(device);
+    }
+}
+
+fn is_cached(lv: &LvReport) -> bool {
+    lv.segtype == "cache" || lv.segtype == "writecache"
+}
+
+/// Whether teardown has removed everything on the device, or it was empty to begin with.
+fn is_released(device: &LsblkBlockDevice, physical_volumes: &[String]) -> bool {
+    if is_our_swap(device) || physical_volumes.contains(&device.path) {
+        return true;
+    }
+    match device.children.as_deref() {
+        Some(children) if !children.is_empty() => children.iter().all(|child| {
+            our_mapping(&child.path).is_some() && is_released(child, physical_volumes)
+        }),
+        _ => device.fstype.is_none(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::error::Error;
+    use crate::teardown::TeardownController;
+    use crate::test::TestEnv;
+    use crate::{Commander, PlanStep};
+
+    fn command(args: &[&str]) -> PlanStep {
+        PlanStep::Command {
+            args: args.iter().map(|arg| (*arg).to_owned()).collect(),
+        }
+    }
+
+    fn mock_devices(test_env: &TestEnv, vg_tags: &str) {
+        test_env.mock(
+            "vgs",
+            0,
+            &format!(
+                r#"{{"report": [{{"vg": [{{"vg_name": "instance-store-vg", "vg_tags": "{vg_tags}"}}]}}]}}"#
+            ),
+        );
+        test_env.mock(
+            "lvs",
+            0,
+            r#"{"report": [{"lv": [
+                {"lv_name": "data", "lv_dm_path": "/dev/mapper/instance--store--vg-data", "segtype": "linear", "lv_device_open": ""}
+            ]}]}"#,
+        );
+        test_env.mock(
+            "pvs",
+            0,
+            r#"{"report": [{"pv": [
+                {"pv_name": "/dev/nvme1n1", "vg_name": "instance-store-vg", "pv_tags": ""},
+                {"pv_name": "/dev/nvme4n1p2", "vg_name": "instance-store-vg", "pv_tags": ""},
+                {"pv_name": "/dev/nvme5n1p1", "vg_name": "somebody-elses-vg", "pv_tags": ""},
+                {"pv_name": "/dev/nvme0n1p2", "vg_name": "somebody-elses-vg", "pv_tags": ""}
+            ]}]}"#,
+        );
+        test_env.mock(
+            "lsblk",
+            0,
+            r#"{"blockdevices": [
+                {"path": "/dev/nvme2n1", "type": "disk", "fstype": "swap", "label": "ephemeral-swap", "mountpoint": "[SWAP]"},
+                {"path": "/dev/nvme3n1", "type": "disk", "fstype": "swap", "label": null, "mountpoint": "[SWAP]"},
+                {"path": "/dev/zram0", "type": "disk", "fstype": "swap", "label": "ephemeral-swap", "mountpoint": "[SWAP]"},
+                {"path": "/dev/mapper/instance--store--vg-swap", "type": "lvm", "fstype": "swap", "label": "ephemeral-swap", "mountpoint": "[SWAP]"},
+                {"path": "/dev/nvme4n1", "type": "disk", "children": [
+                    {"path": "/dev/nvme4n1p1", "type": "part", "fstype": "swap", "label": "ephemeral-swap", "mountpoint": "[SWAP]",
+                     "parttype": "0657fd6d-a4ab-43c4-84e5-0933c84b4f4f", "partlabel": "ephemeral-swap"},
+                    {"path": "/dev/nvme4n1p2", "type": "part", "fstype": "LVM2_member",
+                     "parttype": "e6d6d379-f507-44c2-a23c-238f2a3df928", "partlabel": "ephemeral-lvm",
+                     "children": [{"path": "/dev/mapper/instance--store--vg-data", "type": "lvm"}]}
+                ]},
+                {"path": "/dev/nvme5n1", "type": "disk", "children": [
+                    {"path": "/dev/nvme5n1p1", "type": "part", "fstype": "LVM2_member",
+                     "parttype": "e6d6d379-f507-44c2-a23c-238f2a3df928", "partlabel": "ephemeral-lvm"}
+                ]}
+            ]}"#,
+        );
+    }
+
+    #[tokio::test]
+    async fn test_teardown_dry_run() {
+        let test_env = TestEnv::new();
+        mock_devices(&test_env, "ephemeral-storage-setup");
+        // None of these may run during a dry run.
+        for command in [
+            "swapoff", "zramctl", "vgchange", "vgremove", "pvremove", "wipefs", "sgdisk",
+        ] {
+            test_env.mock(command, 1, "");
+        }
+        let commander = Commander {
+            plan: Some(Default::default()),
+            ..test_env.commander.clone()
+        };
+        TeardownController {
+            commander: commander.clone(),
+            vg_name: "instance-store-vg".to_owned(),
+            wipe: true,
+        }
+        .teardown()
+        .await
+        .unwrap();
+        assert_eq!(
+            vec![
+                command(&["swapoff", "/dev/nvme2n1"]),
+                command(&["swapoff", "/dev/zram0"]),
+                command(&["zramctl", "--reset", "/dev/zram0"]),
+                command(&["swapoff", "/dev/mapper/instance--store--vg-swap"]),
+                command(&["swapoff", "/dev/nvme4n1p1"]),
+                command(&["vgchange", "--activate", "n", "instance-store-vg"]),
+                command(&["vgremove", "--yes", "--force", "instance-store-vg"]),
+                command(&["pvremove", "--yes", "/dev/nvme1n1"]),
+                command(&["pvremove", "--yes", "/dev/nvme4n1p2"]),
+                command(&["wipefs", "--all", "/dev/nvme2n1"]),
+                command(&["wipefs", "--all", "/dev/nvme4n1p1"]),
+                command(&["wipefs", "--all", "/dev/nvme1n1"]),
+                command(&["wipefs", "--all", "/dev/nvme4n1p2"]),
+                // nvme5n1's partition is still in somebody else's volume group.
+                command(&["sgdisk", "--zap-all", "/dev/nvme4n1"]),
+            ],
+            commander.plan()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_teardown_dry_run_swap_volume() {
+        let test_env = TestEnv::new();
+        mock_devices(&test_env, "ephemeral-storage-setup");
+        // Swap on the logical volume is only disabled in the plan, so it still shows as open.
+        let lvs = |data_open: &str| {
+            format!(
+                r#"{{"report": [{{"lv": [
+                    {{"lv_name": "data", "lv_dm_path": "/dev/mapper/instance--store--vg-data", "segtype": "linear", "lv_device_open": "{data_open}"}},
+                    {{"lv_name": "swap", "lv_dm_path": "/dev/mapper/instance--store--vg-swap", "segtype": "linear", "lv_device_open": "open"}}
+                ]}}]}}"#
+            )
+        };
+        test_env.mock("lvs", 0, &lvs(""));
+        let commander = Commander {
+            plan: Some(Default::default()),
+            ..test_env.commander.clone()
+        };
+        let controller = TeardownController {
+            commander: commander.clone(),
+            vg_name: "instance-store-vg".to_owned(),
+            wipe: false,
+        };
+        controller.teardown().await.unwrap();
+        assert!(commander.plan().contains(&command(&[
+            "vgremove",
+            "--yes",
+            "--force",
+            "instance-store-vg"
+        ])));
+
+        // Other logical volumes in use are still refused.
+        test_env.mock("lvs", 0, &lvs("open"));
+        assert!(matches!(
+            controller.teardown().await,
+            Err(Error::Refused(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_teardown_refuses_untagged_volume_group() {
+        let test_env = TestEnv::new();
+        mock_devices(&test_env, "some-other-tag");
+        let result = TeardownController {
+            commander: test_env.commander.clone(),
+            vg_name: "instance-store-vg".to_owned(),
+            wipe: false,
+        }
+        .teardown_volume_group(&[])
+        .await;
+        assert!(matches!(result, Err(Error::Refused(_))));
+    }
+
+    #[tokio::test]
+    async fn test_teardown_cache_keeps_origin() {
+        let test_env = TestEnv::new();
+        test_env.mock(
+            "vgs",
+            0,
+            r#"{"report": [{"vg": [{"vg_name": "instance-store-vg", "vg_tags": "ephemeral-storage-setup"}]}]}"#,
+        );
+        // The origin is in use by its consumer, which is fine since it stays.
+        test_env.mock(
+            "lvs",
+            0,
+            r#"{"report": [{"lv": [
+                {"lv_name": "cached", "lv_dm_path": "/dev/mapper/instance--store--vg-cached", "segtype": "cache", "lv_device_open": "open"}
+            ]}]}"#,
+        );
+        test_env.mock(
+            "pvs",
+            0,
+            r#"{"report": [{"pv": [
+                {"pv_name": "/dev/xvdf", "vg_name": "instance-store-vg", "pv_tags": "ephemeral-storage-setup-origin"},
+                {"pv_name": "/dev/nvme1n1", "vg_name": "instance-store-vg", "pv_tags": ""},
+                {"pv_name": "/dev/nvme2n1", "vg_name": "instance-store-vg", "pv_tags": ""}
+            ]}]}"#,
+        );
+        test_env.mock(
+            "lsblk",
+            0,
+            r#"{"blockdevices": [
+                {"path": "/dev/xvdf", "type": "disk", "fstype": "LVM2_member"},
+                {"path": "/dev/nvme1n1", "type": "disk", "fstype": "LVM2_member"},
+                {"path": "/dev/nvme2n1", "type": "disk", "fstype": "LVM2_member"}
+            ]}"#,
+        );
+        for command in [
+            "lvconvert",
+            "vgchange",
+            "vgremove",
+            "vgreduce",
+            "pvremove",
+            "wipefs",
+            "sgdisk",
+        ] {
+            test_env.mock(command, 1, "");
+        }
+        let commander = Commander {
+            plan: Some(Default::default()),
+            ..test_env.commander.clone()
+        };
+        TeardownController {
+            commander: commander.clone(),
+            vg_name: "instance-store-vg".to_owned(),
+            wipe: true,
+        }
+        .teardown()
+        .await
+        .unwrap();
+        let plan = commander.plan();
+        assert_eq!(
+            vec![
+                command(&[
+                    "lvconvert",
+                    "--yes",
+                    "--uncache",
+                    "instance-store-vg/cached"
+                ]),
+                command(&["vgreduce", "instance-store-vg", "/dev/nvme1n1"]),
+                command(&["vgreduce", "instance-store-vg", "/dev/nvme2n1"]),
+                command(&["pvremove", "--yes", "/dev/nvme1n1"]),
+                command(&["pvremove", "--yes", "/dev/nvme2n1"]),
+                command(&["wipefs", "--all", "/dev/nvme1n1"]),
+                command(&["wipefs", "--all", "/dev/nvme2n1"]),
+            ],
+            plan
+        );
+        assert!(!plan.iter().any(|step| matches!(
+            step,
+            PlanStep::Command { args } if args.iter().any(|arg| arg == "/dev/xvdf")
+        )));
+    }
+}
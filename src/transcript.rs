@@ -0,0 +1,158 @@
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::process::Output;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::Serialize;
+use tracing::{info, warn};
+
+use crate::error::{Error, Result};
+
+/// Where the transcript is kept on the host, unless told otherwise.
+pub const TRANSCRIPT_HOST_PATH: &str = "/var/log/ephemeral-storage-setup.jsonl";
+
+/// Output longer than this many bytes is truncated in the transcript.
+const MAX_OUTPUT_BYTES: usize = 4096;
+
+/// A JSON Lines record of every command we run.
+///
+/// This outlives the pod, so it can be used to find out
+/// which commands ran on a node after the fact.
+pub struct Transcript {
+    path: String,
+    file: Mutex<File>,
+}
+
+#[derive(Serialize, Debug)]
+struct TranscriptEntry<'a> {
+    /// Milliseconds since the unix epoch when the command finished.
+    timestamp_ms: u128,
+    args: &'a [&'a str],
+    /// Environment variables set on top of our own.
+    env: &'a HashMap<String, String>,
+    exit_code: Option<i32>,
+    duration_ms: u128,
+    stdout: Option<String>,
+    stderr: Option<String>,
+    /// Why the command didn't produce an exit code, like a timeout.
+    error: Option<String>,
+}
+
+impl Transcript {
+    /// Opens the transcript at path, appending to it if it exists.
+    pub fn open(path: &str) -> Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|e| Error::io(format!("Failed to open transcript {path}"), e))?;
+        Ok(Transcript {
+            path: path.to_owned(),
+            file: Mutex::new(file),
+        })
+    }
+
+    /// Records a command and its result.
+    /// Failing to write the transcript is logged, but isn't an error.
+    pub(crate) fn record(
+        &self,
+        args: &[&str],
+        env: &HashMap<String, String>,
+        result: &Result<Output>,
+        duration: Duration,
+    ) {
+        let (exit_code, stdout, stderr, error) = match result {
+            Ok(output) => (
+                output.status.code(),
+                Some(truncate(&output.stdout)),
+                Some(truncate(&output.stderr)),
+                None,
+            ),
+            Err(e) => (None, None, None, Some(e.to_string())),
+        };
+        let entry = TranscriptEntry {
+            timestamp_ms: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis(),
+            args,
+            env,
+            exit_code,
+            duration_ms: duration.as_millis(),
+            stdout,
+            stderr,
+            error,
+        };
+        info!(
+            args = ?entry.args,
+            exit_code = ?entry.exit_code,
+            duration_ms = entry.duration_ms,
+            error = entry.error,
+            "Ran command"
+        );
+
+        let mut line = serde_json::to_string(&entry).unwrap();
+        line.push('\n');
+        let mut file = self.file.lock().unwrap();
+        if let Err(e) = file.write_all(line.as_bytes()) {
+            warn!("Failed to write to transcript {}: {e}", self.path);
+        }
+    }
+}
+
+fn truncate(output: &[u8]) -> String {
+    let output = String::from_utf8_lossy(output);
+    if output.len() <= MAX_OUTPUT_BYTES {
+        return output.into_owned();
+    }
+    let mut end = MAX_OUTPUT_BYTES;
+    while !output.is_char_boundary(end) {
+        end -= 1;
+    }
+    format!(
+        "{}... ({} bytes truncated)",
+        &output[..end],
+        output.len() - end
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use crate::test::TestEnv;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_transcript() {
+        let test_env = TestEnv::new();
+        let path = test_env.temp_dir.path().join("transcript.jsonl");
+        let transcript = Transcript::open(path.to_str().unwrap()).unwrap();
+        let commander = test_env.commander.clone().with_transcript(transcript);
+        test_env.mock("ok", 0, &"x".repeat(MAX_OUTPUT_BYTES * 2));
+        test_env.mock("fail", 3, "");
+        commander.check_output(&["ok", "arg"]).await.unwrap();
+        commander.check_output(&["fail"]).await.unwrap_err();
+        commander.check_output(&["missing"]).await.unwrap_err();
+
+        let entries: Vec<serde_json::Value> = std::fs::read_to_string(&path)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(entries.len(), 3);
+        assert_eq!(entries[0]["args"], serde_json::json!(["ok", "arg"]));
+        assert_eq!(entries[0]["exit_code"], 0);
+        assert_eq!(entries[0]["env"]["NODE_NAME"], "test-node");
+        assert!(
+            entries[0]["stdout"]
+                .as_str()
+                .unwrap()
+                .ends_with("bytes truncated)")
+        );
+        assert_eq!(entries[1]["exit_code"], 3);
+        assert!(entries[2]["exit_code"].is_null());
+        assert!(entries[2]["error"].as_str().unwrap().contains("missing"));
+    }
+}
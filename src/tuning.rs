@@ -0,0 +1,421 @@
+use std::collections::BTreeMap;
+use std::fs;
+
+use clap::ValueEnum;
+use tracing::{debug, info, warn};
+
+use crate::Commander;
+use crate::detect::is_bottlerocket;
+use crate::error::{Error, Result};
+
+/// Where the kernel exposes sysctls as files.
+const PROC_SYS_PATH: &str = "/proc/sys";
+
+/// Where we persist sysctls on the host, for systemd-sysctl to apply at boot.
+const SYSCTL_CONF_PATH: &str = "/etc/sysctl.d/99-ephemeral-storage-setup.conf";
+/// Where we persist sysfs settings on the host, for systemd-tmpfiles to write at boot.
+const TMPFILES_CONF_PATH: &str = "/etc/tmpfiles.d/99-ephemeral-storage-setup.conf";
+
+/// Where the zswap module takes its parameters.
+const ZSWAP_PARAMETERS_PATH: &str = "/sys/module/zswap/parameters";
+
+/// Settings for zswap, a compressed cache in RAM for pages on their way to swap.
+#[derive(Clone, Debug)]
+pub struct ZswapConfig {
+    /// Compression algorithm, like `zstd` or `lz4`.
+    pub compressor: String,
+    /// Allocator for the compressed pages, like `zsmalloc`.
+    /// Newer kernels only have zsmalloc, and no longer take this parameter.
+    pub zpool: Option<String>,
+    /// Most of RAM the pool may use, as a percentage.
+    pub max_pool_percent: u8,
+}
+
+/// Named sets of kernel tuning.
+#[derive(ValueEnum, Clone, Copy, Debug)]
+pub enum TuningProfile {
+    /// Tuning for Materialize using the ephemeral disks as swap.
+    /// Swap readahead is disabled, as it only helps on rotational disks.
+    MaterializeSwap,
+}
+
+/// Kernel settings to apply, as sysctls and sysfs files.
+///
+/// Settings are applied in key order, and setting the same key twice
+/// keeps the last value.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Tuning {
+    /// Sysctl names, like `vm.swappiness`, and their values.
+    pub sysctls: BTreeMap<String, String>,
+    /// Paths under /sys, and the values to write to them.
+    pub sysfs: BTreeMap<String, String>,
+}
+
+impl Tuning {
+    pub fn profile(profile: TuningProfile) -> Self {
+        let settings = |settings: &[(&str, &str)]| {
+            settings
+                .iter()
+                .map(|(key, value)| (key.to_string(), value.to_string()))
+                .collect()
+        };
+        match profile {
+            TuningProfile::MaterializeSwap => Tuning {
+                sysctls: settings(&[
+                    ("vm.swappiness", "100"),
+                    ("vm.min_free_kbytes", "1048576"),
+                    ("vm.watermark_scale_factor", "100"),
+                    ("vm.page-cluster", "0"),
+                ]),
+                sysfs: settings(&[("/sys/kernel/mm/transparent_hugepage/enabled", "madvise")]),
+            },
+        }
+    }
+
+    /// Enables zswap with the settings.
+    pub fn zswap(config: &ZswapConfig) -> Self {
+        let mut sysfs = BTreeMap::new();
+        let mut set = |name: &str, value: String| {
+            sysfs.insert(format!("{ZSWAP_PARAMETERS_PATH}/{name}"), value)
+        };
+        set("enabled", "Y".to_owned());
+        set("compressor", config.compressor.clone());
+        if let Some(zpool) = &config.zpool {
+            set("zpool", zpool.clone());
+        }
+        set("max_pool_percent", config.max_pool_percent.to_string());
+        Tuning {
+            sysctls: BTreeMap::new(),
+            sysfs,
+        }
+    }
+
+    /// Adds the other tuning, replacing any settings in both.
+    pub fn extend(&mut self, other: Tuning) {
+        self.sysctls.extend(other.sysctls);
+        self.sysfs.extend(other.sysfs);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.sysctls.is_empty() && self.sysfs.is_empty()
+    }
+}
+
+/// Parses a `--sysctl` argument, like `vm.swappiness=100`.
+pub fn parse_sysctl(arg: &str) -> Result<(String, String), String> {
+    let (key, value) = split_setting(arg)?;
+    let valid_char = |c: char| c.is_ascii_alphanumeric() || "._-/".contains(c);
+    if !key.chars().all(valid_char) || key.split(['.', '/']).any(|part| part.is_empty()) {
+        return Err(format!("invalid sysctl name '{key}'"));
+    }
+    Ok((key, value))
+}
+
+/// Parses a `--sysfs` argument, like `/sys/kernel/mm/transparent_hugepage/defrag=madvise`.
+pub fn parse_sysfs(arg: &str) -> Result<(String, String), String> {
+    let (path, value) = split_setting(arg)?;
+    if !path.starts_with("/sys/") || path.split('/').any(|part| part == "..") {
+        return Err(format!("'{path}' is not a path under /sys"));
+    }
+    Ok((path, value))
+}
+
+fn split_setting(arg: &str) -> Result<(String, String), String> {
+    let Some((key, value)) = arg.split_once('=') else {
+        return Err(format!("expected key=value, got '{arg}'"));
+    };
+    let (key, value) = (key.trim(), value.trim());
+    if key.is_empty() || value.is_empty() || value.contains('\n') {
+        return Err(format!("expected key=value, got '{arg}'"));
+    }
+    Ok((key.to_owned(), value.to_owned()))
+}
+
+/// Applies [`Tuning`] to the running kernel, checking that each setting took effect.
+pub(crate) struct Tuner<'a> {
+    commander: &'a Commander,
+    proc_sys: String,
+}
+
+impl<'a> Tuner<'a> {
+    pub(crate) fn new(commander: &'a Commander) -> Self {
+        Tuner {
+            commander,
+            proc_sys: PROC_SYS_PATH.to_owned(),
+        }
+    }
+
+    pub(crate) async fn apply(&self, tuning: &Tuning) -> Result<()> {
+        let mut previous = BTreeMap::new();
+        for key in tuning.sysctls.keys() {
+            previous.insert(key, read_setting(&self.sysctl_path(key))?);
+        }
+        for path in tuning.sysfs.keys() {
+            previous.insert(path, read_setting(path)?);
+        }
+
+        if is_bottlerocket()? && !tuning.sysctls.is_empty() {
+            // We can't set sysctls from a Bottlerocket container,
+            // but its API can, and keeps them across reboots.
+            let settings: Vec<String> = tuning
+                .sysctls
+                .iter()
+                .map(|(key, value)| format!(r#"settings.kernel.sysctl."{key}"={value}"#))
+                .collect();
+            let mut args = vec!["apiclient", "set"];
+            args.extend(settings.iter().map(String::as_str));
+            self.commander.idempotent_output(&args).await?;
+        } else {
+            for (key, value) in &tuning.sysctls {
+                self.commander
+                    .idempotent_output(&["sysctl", &format!("{key}={value}")])
+                    .await?;
+            }
+        }
+        for (path, value) in &tuning.sysfs {
+            self.commander.write_file(path, value)?;
+        }
+
+        let settings = tuning
+            .sysctls
+            .iter()
+            .map(|(key, value)| (key, self.sysctl_path(key), value))
+            .chain(
+                tuning
+                    .sysfs
+                    .iter()
+                    .map(|(path, value)| (path, path.clone(), value)),
+            );
+        for (key, path, value) in settings {
+            if !self.commander.is_dry_run() {
+                let actual = read_setting(&path)?;
+                if !setting_matches(&actual, value) {
+                    return Err(Error::TuningNotApplied {
+                        key: key.clone(),
+                        wanted: value.clone(),
+                        actual,
+                    });
+                }
+            }
+            info!("Set {key} to {value}, was {}", previous[key]);
+        }
+        Ok(())
+    }
+
+    /// Writes the tuning to the host's config, so it's applied again at boot.
+    ///
+    /// Replaces anything we persisted before, and removes our files if there's nothing to persist.
+    pub(crate) fn persist(&self, tuning: &Tuning) -> Result<()> {
+        let sysctl_conf: String = tuning
+            .sysctls
+            .iter()
+            .map(|(key, value)| format!("{key} = {value}\n"))
+            .collect();
+        // The tmpfiles "w" type writes the argument to the path, if it exists.
+        let tmpfiles_conf: String = tuning
+            .sysfs
+            .iter()
+            .map(|(path, value)| format!("w {path} - - - - {value}\n"))
+            .collect();
+
+        if is_bottlerocket()? {
+            // The apiclient already persisted the sysctls, and /etc doesn't survive reboots.
+            if !tuning.sysfs.is_empty() {
+                warn!("Can't persist sysfs settings on Bottlerocket");
+            }
+            return Ok(());
+        }
+        self.persist_file(SYSCTL_CONF_PATH, &sysctl_conf)?;
+        self.persist_file(TMPFILES_CONF_PATH, &tmpfiles_conf)
+    }
+
+    fn persist_file(&self, path: &str, settings: &str) -> Result<()> {
+        let path = self.commander.host_path(path);
+        if settings.is_empty() {
+            return self.commander.remove_file(&path);
+        }
+        let contents = format!("# Managed by ephemeral-storage-setup.\n{settings}");
+        if fs::read_to_string(&path).is_ok_and(|existing| existing == contents) {
+            debug!("{path} is already up to date");
+            return Ok(());
+        }
+        info!("Persisting tuning in {path}");
+        self.commander.write_file(&path, &contents)
+    }
+
+    fn sysctl_path(&self, key: &str) -> String {
+        // The files are nested where the names have dots.
+        format!("{}/{}", self.proc_sys, key.replace('.', "/"))
+    }
+}
+
+/// Removes the tuning persisted on the host, leaving the running kernel alone.
+pub(crate) fn remove_persisted(commander: &Commander) -> Result<()> {
+    for path in [SYSCTL_CONF_PATH, TMPFILES_CONF_PATH] {
+        commander.remove_file(&commander.host_path(path))?;
+    }
+    Ok(())
+}
+
+fn read_setting(path: &str) -> Result<String> {
+    fs::read_to_string(path)
+        .map(|value| value.trim().to_owned())
+        .map_err(|e| Error::io(format!("Failed to read {path}"), e))
+}
+
+/// Whether a value read back matches what we wrote.
+///
+/// Whitespace is normalized, since multi-value sysctls are read back tab separated,
+/// and sysfs choices are read back as all options with the selected one in brackets,
+/// like `always [madvise] never`.
+fn setting_matches(actual: &str, wanted: &str) -> bool {
+    let normalize = |value: &str| value.split_whitespace().collect::<Vec<_>>().join(" ");
+    let selected = actual
+        .split_whitespace()
+        .find_map(|option| option.strip_prefix('[')?.strip_suffix(']'));
+    normalize(actual) == normalize(wanted) || selected == Some(wanted)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::PlanStep;
+    use crate::test::TestEnv;
+
+    #[test]
+    fn test_parse_settings() {
+        assert_eq!(
+            parse_sysctl("vm.swappiness=100"),
+            Ok(("vm.swappiness".to_owned(), "100".to_owned()))
+        );
+        assert_eq!(
+            parse_sysctl("net.ipv4.ip_local_port_range=32768 60999"),
+            Ok((
+                "net.ipv4.ip_local_port_range".to_owned(),
+                "32768 60999".to_owned()
+            ))
+        );
+        assert!(parse_sysctl("vm.swappiness").is_err());
+        assert!(parse_sysctl("vm..swappiness=1").is_err());
+        assert!(parse_sysctl("../../etc/passwd=1").is_err());
+        assert!(parse_sysctl("vm.swappiness=").is_err());
+
+        assert_eq!(
+            parse_sysfs("/sys/kernel/mm/lru_gen/enabled=y"),
+            Ok(("/sys/kernel/mm/lru_gen/enabled".to_owned(), "y".to_owned()))
+        );
+        assert!(parse_sysfs("/etc/passwd=x").is_err());
+        assert!(parse_sysfs("/sys/../etc/passwd=x").is_err());
+    }
+
+    #[test]
+    fn test_setting_matches() {
+        assert!(setting_matches("100", "100"));
+        assert!(setting_matches("32768\t60999", "32768 60999"));
+        assert!(setting_matches("always [madvise] never", "madvise"));
+        assert!(!setting_matches("[always] madvise never", "madvise"));
+        assert!(!setting_matches("60", "100"));
+    }
+
+    #[test]
+    fn test_zswap() {
+        let tuning = Tuning::zswap(&ZswapConfig {
+            compressor: "zstd".to_owned(),
+            zpool: None,
+            max_pool_percent: 20,
+        });
+        assert!(tuning.sysctls.is_empty());
+        assert_eq!(
+            tuning.sysfs.into_iter().collect::<Vec<_>>(),
+            vec![
+                (
+                    "/sys/module/zswap/parameters/compressor".to_owned(),
+                    "zstd".to_owned()
+                ),
+                (
+                    "/sys/module/zswap/parameters/enabled".to_owned(),
+                    "Y".to_owned()
+                ),
+                (
+                    "/sys/module/zswap/parameters/max_pool_percent".to_owned(),
+                    "20".to_owned()
+                ),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_apply() {
+        let test_env = TestEnv::new();
+        let dir = test_env.temp_dir.path().to_str().unwrap();
+        fs::create_dir_all(format!("{dir}/proc/sys/vm")).unwrap();
+        fs::write(format!("{dir}/proc/sys/vm/swappiness"), "60\n").unwrap();
+        fs::write(format!("{dir}/enabled"), "[always] madvise never\n").unwrap();
+        // Writes the sysctl like the real one, given a name with dots.
+        test_env.mock_script(
+            "sysctl",
+            &format!(
+                r#"key="${{1%%=*}}"; echo "${{1#*=}}" > "{dir}/proc/sys/$(echo "$key" | tr . /)""#
+            ),
+        );
+        let tuner = Tuner {
+            commander: &test_env.commander,
+            proc_sys: format!("{dir}/proc/sys"),
+        };
+
+        let mut tuning = Tuning::default();
+        tuning
+            .sysctls
+            .insert("vm.swappiness".to_owned(), "100".to_owned());
+        tuning
+            .sysfs
+            .insert(format!("{dir}/enabled"), "madvise".to_owned());
+        tuner.apply(&tuning).await.unwrap();
+        assert_eq!(
+            fs::read_to_string(format!("{dir}/proc/sys/vm/swappiness")).unwrap(),
+            "100\n"
+        );
+        assert_eq!(
+            fs::read_to_string(format!("{dir}/enabled")).unwrap(),
+            "madvise"
+        );
+
+        // A value the kernel didn't accept is read back as something else.
+        test_env.mock("sysctl", 0, "");
+        tuning
+            .sysctls
+            .insert("vm.swappiness".to_owned(), "200".to_owned());
+        let result = tuner.apply(&tuning).await;
+        assert!(matches!(
+            result,
+            Err(Error::TuningNotApplied { wanted, actual, .. }) if wanted == "200" && actual == "100"
+        ));
+
+        tuning
+            .sysctls
+            .insert("vm.missing".to_owned(), "1".to_owned());
+        let result = tuner.apply(&tuning).await;
+        assert!(matches!(result, Err(Error::Io { .. })));
+    }
+
+    #[test]
+    fn test_persist() {
+        let commander = Commander::dry_run();
+        let tuning = Tuning {
+            sysctls: BTreeMap::new(),
+            ..Tuning::profile(TuningProfile::MaterializeSwap)
+        };
+        Tuner::new(&commander).persist(&tuning).unwrap();
+        assert_eq!(
+            commander.plan(),
+            // There's no sysctl config to remove.
+            vec![PlanStep::WriteFile {
+                path: format!("/host{TMPFILES_CONF_PATH}"),
+                contents: "# Managed by ephemeral-storage-setup.
+w /sys/kernel/mm/transparent_hugepage/enabled - - - - madvise
+"
+                .to_owned(),
+            }]
+        );
+    }
+}
@@ -0,0 +1,170 @@
+use tracing::info;
+
+use crate::Commander;
+use crate::detect::is_bottlerocket;
+use crate::error::{Error, Result};
+use crate::swap::{SWAP_LABEL, SwapDiscard, swapon_args};
+
+/// Exists once the zram module is loaded.
+const ZRAM_CONTROL_PATH: &str = "/sys/class/zram-control";
+
+/// Stands in for the device zramctl would pick, in a dry run.
+const DRY_RUN_DEVICE: &str = "/dev/zram<new>";
+
+/// A compressed swap device in RAM, used before the disks.
+#[derive(Clone, Debug)]
+pub struct ZramConfig {
+    /// Uncompressed size of the device, like `8G`.
+    pub size: String,
+    /// Compression algorithm, like `zstd` or `lz4`.
+    pub algorithm: String,
+    /// Swap priority, which must be higher than the disks' so it's used first.
+    pub priority: i32,
+}
+
+impl ZramConfig {
+    /// Creates a zram device and enables swap on it, unless we already have.
+    /// Returns the path of the device.
+    pub(crate) async fn setup(&self, commander: &Commander) -> Result<String> {
+        if let Some(device) = existing_device(commander).await? {
+            info!("zram swap already exists on {device}");
+            return Ok(device);
+        }
+        if !std::fs::exists(ZRAM_CONTROL_PATH)
+            .map_err(|e| Error::io(format!("Failed to check {ZRAM_CONTROL_PATH}"), e))?
+        {
+            // Bottlerocket's root filesystem has no modprobe, so the module
+            // has to be loaded through its settings before we run.
+            if is_bottlerocket()? {
+                return Err(Error::Config(
+                    "the zram module isn't loaded, and can't be loaded on Bottlerocket from here"
+                        .to_owned(),
+                ));
+            }
+            info!("Loading the zram module on the host");
+            commander
+                .on_host()
+                .idempotent_output(&["modprobe", "zram"])
+                .await?;
+        }
+
+        info!(
+            "Creating a {} zram device compressed with {}",
+            self.size, self.algorithm
+        );
+        let output = commander
+            .mutating_output(&[
+                "zramctl",
+                "--find",
+                "--size",
+                &self.size,
+                "--algorithm",
+                &self.algorithm,
+            ])
+            .await?;
+        let device = String::from_utf8_lossy(&output.stdout).trim().to_owned();
+        let device = match device.is_empty() && commander.is_dry_run() {
+            true => DRY_RUN_DEVICE.to_owned(),
+            false => device,
+        };
+
+        commander
+            .mutating_output(&["mkswap", "-L", SWAP_LABEL, &device])
+            .await?;
+        let args = swapon_args(&device, self.priority, SwapDiscard::None);
+        let args: Vec<&str> = args.iter().map(String::as_str).collect();
+        commander.mutating_output(&args).await?;
+        Ok(device)
+    }
+}
+
+/// Finds a zram device we already enabled swap on.
+async fn existing_device(commander: &Commander) -> Result<Option<String>> {
+    let swaps = std::fs::read_to_string("/proc/swaps")
+        .map_err(|e| Error::io("Failed to read /proc/swaps", e))?;
+    for device in zram_swaps(&swaps) {
+        let output = commander
+            .check_output(&["blkid", "--match-tag", "LABEL", "--output", "value", device])
+            .await?;
+        if String::from_utf8_lossy(&output.stdout).trim() == SWAP_LABEL {
+            return Ok(Some(device.to_owned()));
+        }
+    }
+    Ok(None)
+}
+
+/// The zram devices listed in /proc/swaps.
+fn zram_swaps(swaps: &str) -> impl Iterator<Item = &str> {
+    swaps
+        .lines()
+        .skip(1)
+        .filter_map(|line| line.split_whitespace().next())
+        .filter(|filename| is_zram(filename))
+}
+
+pub(crate) fn is_zram(path: &str) -> bool {
+    path.strip_prefix("/dev/zram")
+        .is_some_and(|number| !number.is_empty() && number.chars().all(|c| c.is_ascii_digit()))
+}
+
+/// Frees a zram device and the memory it used.
+pub(crate) async fn reset(commander: &Commander, device: &str) -> Result<()> {
+    info!("Resetting {device}");
+    commander
+        .mutating_output(&["zramctl", "--reset", device])
+        .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::PlanStep;
+    use crate::test::TestEnv;
+
+    #[test]
+    fn test_zram_swaps() {
+        let swaps = "Filename\t\t\t\tType\t\tSize\t\tUsed\t\tPriority
+/dev/nvme1n1                            partition\t393215996\t0\t\t10
+/dev/zram0                              partition\t8388604\t0\t\t100
+/dev/zram-other                         partition\t8388604\t0\t\t100
+";
+        assert_eq!(zram_swaps(swaps).collect::<Vec<_>>(), vec!["/dev/zram0"]);
+    }
+
+    #[tokio::test]
+    async fn test_setup_dry_run() {
+        let test_env = TestEnv::new();
+        test_env.mock("blkid", 0, "");
+        let commander = Commander {
+            plan: Some(Default::default()),
+            ..test_env.commander.clone()
+        };
+        let config = ZramConfig {
+            size: "8G".to_owned(),
+            algorithm: "zstd".to_owned(),
+            priority: 100,
+        };
+        let device = config.setup(&commander).await.unwrap();
+        assert_eq!(device, DRY_RUN_DEVICE);
+        let commands: Vec<PlanStep> = commander
+            .plan()
+            .into_iter()
+            // Whether the module needs loading depends on the machine running the test.
+            .filter(|step| {
+                !matches!(step, PlanStep::Command { args } if args.iter().any(|arg| arg == "modprobe"))
+            })
+            .collect();
+        let command = |args: &[&str]| PlanStep::Command {
+            args: args.iter().map(|arg| arg.to_string()).collect(),
+        };
+        assert_eq!(
+            commands,
+            vec![
+                command(&["zramctl", "--find", "--size", "8G", "--algorithm", "zstd"]),
+                command(&["mkswap", "-L", SWAP_LABEL, DRY_RUN_DEVICE]),
+                command(&["swapon", "-p", "100", DRY_RUN_DEVICE]),
+            ]
+        );
+    }
+}